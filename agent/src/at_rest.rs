@@ -0,0 +1,336 @@
+//! 本地文件静态加密
+//!
+//! 配置 `at_rest_encryption_key` 后，持久化状态、耗时历史等整体读写的文件使用 AES-256-GCM 加密存储；
+//! 任务输出日志边写边按偏移读取，使用 AES-256-CTR 逐字节加密（见 [`LogFileWriter`]）。
+//! 读取时兼容未加密的旧文件，便于开启加密后平滑迁移。
+
+use aes::Aes256;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::{AgentError, Result};
+
+/// 加密文件头，用于区分加密文件与明文文件
+const ENCRYPTED_MAGIC: &[u8] = b"TNXAES1:";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// 加密日志文件头，其后为 16 字节随机 IV
+const LOG_MAGIC: &[u8] = b"TNXCTR1:";
+const LOG_IV_LEN: usize = 16;
+const LOG_HEADER_LEN: u64 = (LOG_MAGIC.len() + LOG_IV_LEN) as u64;
+/// 日志密钥由主密钥派生，与整体加密的文件不共用 AES 密钥
+const LOG_KEY_CONTEXT: &[u8] = b"tasknexus-at-rest-log\0";
+
+type LogStreamCipher = ctr::Ctr128BE<Aes256>;
+
+/// 静态加密器
+#[derive(Clone)]
+pub struct AtRestCipher {
+    cipher: Aes256Gcm,
+    log_key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for AtRestCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AtRestCipher(<redacted>)")
+    }
+}
+
+impl AtRestCipher {
+    /// 从配置值解析密钥
+    ///
+    /// 支持 `env:NAME`（读取环境变量）或 `file:/path`（读取密钥文件），密钥内容为 base64 编码的 32 字节。
+    /// 不接受直接写在配置中的密钥，避免密钥随配置文件泄露。错误信息中不会包含密钥本身。
+    pub fn from_config_value(value: &str) -> Result<Self> {
+        let encoded = if let Some(name) = value.strip_prefix("env:") {
+            std::env::var(name).map_err(|_| {
+                AgentError::Config(format!(
+                    "at_rest_encryption_key: environment variable '{}' is not set",
+                    name
+                ))
+            })?
+        } else if let Some(path) = value.strip_prefix("file:") {
+            std::fs::read_to_string(path).map_err(|e| {
+                AgentError::Config(format!(
+                    "at_rest_encryption_key: failed to read key file '{}': {}",
+                    path, e
+                ))
+            })?
+        } else {
+            return Err(AgentError::Config(
+                "at_rest_encryption_key must be given as env:NAME or file:/path, inline keys are not accepted"
+                    .to_string(),
+            ));
+        };
+
+        let key = BASE64.decode(encoded.trim()).map_err(|_| {
+            AgentError::Config("at_rest_encryption_key is not valid base64".to_string())
+        })?;
+        if key.len() != KEY_LEN {
+            return Err(AgentError::Config(format!(
+                "at_rest_encryption_key must decode to {} bytes",
+                KEY_LEN
+            )));
+        }
+        Self::from_key(&key)
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<Self> {
+        let cipher = Aes256Gcm::new_from_slice(key)
+            .map_err(|_| AgentError::Config(format!("Encryption key must be {} bytes", KEY_LEN)))?;
+        let log_key = Sha256::new()
+            .chain_update(LOG_KEY_CONTEXT)
+            .chain_update(key)
+            .finalize()
+            .into();
+        Ok(Self { cipher, log_key })
+    }
+
+    /// 加密，输出格式为 文件头 + nonce + 密文
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| AgentError::Execution("Failed to encrypt data at rest".to_string()))?;
+
+        let mut output = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+        output.extend_from_slice(ENCRYPTED_MAGIC);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+        Ok(output)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let body = data
+            .strip_prefix(ENCRYPTED_MAGIC)
+            .filter(|body| body.len() >= NONCE_LEN)
+            .ok_or_else(|| AgentError::Execution("Data is not encrypted at rest".to_string()))?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                AgentError::Execution(
+                    "Failed to decrypt data at rest (wrong key or corrupted file)".to_string(),
+                )
+            })
+    }
+}
+
+/// 任务输出日志的加密器（AES-256-CTR）
+///
+/// 密文与明文逐字节对应，追加写入和按明文偏移读取都无需换算；CTR 不校验完整性，
+/// 只用于边写边读、无法整体加密的日志文件。
+#[derive(Clone)]
+pub struct LogCipher {
+    key: [u8; KEY_LEN],
+    iv: [u8; LOG_IV_LEN],
+}
+
+impl fmt::Debug for LogCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogCipher(<redacted>)")
+    }
+}
+
+impl LogCipher {
+    /// 原地加密或解密从明文偏移 `offset` 开始的数据
+    fn apply(&self, offset: u64, data: &mut [u8]) {
+        let mut cipher = LogStreamCipher::new(&self.key.into(), &self.iv.into());
+        cipher.seek(offset);
+        cipher.apply_keystream(data);
+    }
+}
+
+/// 追加写入的任务输出日志，配置密钥时写入文件头并逐字节加密
+pub struct LogFileWriter {
+    writer: BufWriter<File>,
+    cipher: Option<LogCipher>,
+    /// 已写入的明文字节数
+    offset: u64,
+}
+
+impl LogFileWriter {
+    /// 创建日志文件，已存在时覆盖
+    pub fn create(path: &Path, cipher: Option<&AtRestCipher>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let cipher = match cipher {
+            Some(cipher) => {
+                let mut iv = [0u8; LOG_IV_LEN];
+                OsRng.fill_bytes(&mut iv);
+                writer.write_all(LOG_MAGIC)?;
+                writer.write_all(&iv)?;
+                Some(LogCipher {
+                    key: cipher.log_key,
+                    iv,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            writer,
+            cipher,
+            offset: 0,
+        })
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match &self.cipher {
+            Some(cipher) => {
+                let mut sealed = data.to_vec();
+                cipher.apply(self.offset, &mut sealed);
+                self.writer.write_all(&sealed)?;
+            }
+            None => self.writer.write_all(data)?,
+        }
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// 读取本文件时使用的加密器，未加密时为 `None`
+    pub fn cipher(&self) -> Option<&LogCipher> {
+        self.cipher.as_ref()
+    }
+}
+
+/// 从日志文件的明文偏移 `offset` 处读取至多 `max_bytes` 字节（已解密）
+pub fn read_log_at(
+    path: &Path,
+    cipher: Option<&LogCipher>,
+    offset: u64,
+    max_bytes: usize,
+) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let header_len = if cipher.is_some() { LOG_HEADER_LEN } else { 0 };
+    file.seek(SeekFrom::Start(header_len + offset))?;
+    let mut data = Vec::with_capacity(max_bytes);
+    file.take(max_bytes as u64).read_to_end(&mut data)?;
+    if let Some(cipher) = cipher {
+        cipher.apply(offset, &mut data);
+    }
+    Ok(data)
+}
+
+/// 读取整个日志文件；带加密文件头时用 `cipher` 解密，明文文件原样返回
+pub fn read_log(path: &Path, cipher: Option<&AtRestCipher>) -> Result<Vec<u8>> {
+    let mut data = fs::read(path)?;
+    let Some(body) = data.strip_prefix(LOG_MAGIC) else {
+        return Ok(data);
+    };
+    let cipher = cipher.ok_or_else(|| {
+        AgentError::Config(
+            "Log is encrypted but at_rest_encryption_key is not configured".to_string(),
+        )
+    })?;
+    let iv: [u8; LOG_IV_LEN] = body
+        .get(..LOG_IV_LEN)
+        .and_then(|iv| iv.try_into().ok())
+        .ok_or_else(|| AgentError::Execution("Encrypted log header is truncated".to_string()))?;
+    let log_cipher = LogCipher {
+        key: cipher.log_key,
+        iv,
+    };
+    let mut plaintext = data.split_off(LOG_HEADER_LEN as usize);
+    log_cipher.apply(0, &mut plaintext);
+    Ok(plaintext)
+}
+
+/// 先写同目录下的临时文件再重命名，进程崩溃时不会留下半截的文件
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = Path::new(&temp_name);
+    fs::write(temp_path, data)?;
+    fs::rename(temp_path, path)
+}
+
+/// 判断数据是否为加密格式
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// 写入前加密（未配置密钥时原样返回）
+pub fn seal(cipher: Option<&AtRestCipher>, plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.encrypt(&plaintext),
+        None => Ok(plaintext),
+    }
+}
+
+/// 读取后解密（明文文件原样返回）
+pub fn open(cipher: Option<&AtRestCipher>, data: Vec<u8>) -> Result<Vec<u8>> {
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    match cipher {
+        Some(cipher) => cipher.decrypt(&data),
+        None => Err(AgentError::Config(
+            "File is encrypted but at_rest_encryption_key is not configured".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_log_at, AtRestCipher, LogFileWriter};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn config_value_reads_key_file_and_rejects_inline_key() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let path = std::env::temp_dir().join(format!("tasknexus_at_rest_key_{}", unique));
+        let encoded = "BQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQU=";
+        std::fs::write(&path, format!("{}\n", encoded)).unwrap();
+
+        assert!(AtRestCipher::from_config_value(&format!("file:{}", path.display())).is_ok());
+        let err = AtRestCipher::from_config_value(encoded).unwrap_err();
+        assert!(err.to_string().contains("inline keys are not accepted"));
+        assert!(!err.to_string().contains(encoded));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn encrypted_log_reads_back_at_plaintext_offsets() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let path = std::env::temp_dir().join(format!("tasknexus_log_cipher_{}.log", unique));
+        let cipher = AtRestCipher::from_key(&[5u8; 32]).unwrap();
+
+        let mut writer = LogFileWriter::create(&path, Some(&cipher)).unwrap();
+        writer.write_all(b"first line\n").unwrap();
+        writer.write_all(b"secret token\n").unwrap();
+        writer.flush().unwrap();
+        let log_cipher = writer.cipher().cloned();
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("secret"));
+        assert_eq!(
+            read_log_at(&path, log_cipher.as_ref(), 11, 6).unwrap(),
+            b"secret"
+        );
+        assert_eq!(
+            read_log_at(&path, log_cipher.as_ref(), 0, 1024).unwrap(),
+            b"first line\nsecret token\n"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
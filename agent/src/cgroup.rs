@@ -0,0 +1,185 @@
+//! 任务进程的 cgroup v2 资源限制（仅 Linux）
+//!
+//! 每次执行在 `task_cgroup_root` 下创建独立的子 cgroup 并写入 `memory.max` / `cpu.max`，
+//! 子进程在 exec 前把自己加入其中，执行结束后读取 OOM 计数并删除该 cgroup。
+//! `task_cgroup_root` 必须位于 cgroup v2 层级且对 Agent 可写（例如 systemd `Delegate=yes` 的子树）。
+
+use crate::executor::TaskResourceLimits;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// `cpu.max` 的调度周期(微秒)
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// 内核允许的最小 CPU 配额(微秒)
+const MIN_CPU_QUOTA_US: u64 = 1_000;
+
+/// 删除 cgroup 时等待其中进程退出的重试次数与间隔
+const REMOVE_RETRIES: u32 = 20;
+const REMOVE_RETRY_INTERVAL_MS: u64 = 50;
+
+/// 单次执行的 cgroup
+#[derive(Debug)]
+pub struct TaskCgroup {
+    path: PathBuf,
+    procs_path: CString,
+    memory_max: Option<u64>,
+}
+
+impl TaskCgroup {
+    /// 在 `limits.cgroup_root` 下创建名为 `name` 的 cgroup 并写入限制
+    ///
+    /// 根目录不存在时创建，并在其 `cgroup.subtree_control` 中启用所需的控制器。
+    pub fn create(name: &str, limits: &TaskResourceLimits) -> io::Result<Self> {
+        let root = &limits.cgroup_root;
+        std::fs::create_dir_all(root).map_err(|e| with_path(e, root))?;
+
+        let mut controllers = Vec::new();
+        if limits.memory_max.is_some() {
+            controllers.push("+memory");
+        }
+        if limits.cpu_quota.is_some() {
+            controllers.push("+cpu");
+        }
+        let subtree_control = root.join("cgroup.subtree_control");
+        std::fs::write(&subtree_control, controllers.join(" "))
+            .map_err(|e| with_path(e, &subtree_control))?;
+
+        let path = root.join(name);
+        let procs_path = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?;
+        std::fs::create_dir(&path).map_err(|e| with_path(e, &path))?;
+        let cgroup = Self {
+            path,
+            procs_path,
+            memory_max: limits.memory_max,
+        };
+        if let Err(e) = cgroup.apply_limits(limits) {
+            let _ = std::fs::remove_dir(&cgroup.path);
+            return Err(e);
+        }
+        Ok(cgroup)
+    }
+
+    fn apply_limits(&self, limits: &TaskResourceLimits) -> io::Result<()> {
+        if let Some(bytes) = limits.memory_max {
+            self.write("memory.max", &bytes.to_string())?;
+            // 禁用 swap 使内存上限立即触发 OOM；未启用 swap 控制器时忽略
+            if let Err(e) = self.write("memory.swap.max", "0") {
+                debug!("Failed to disable swap for {:?}: {}", self.path, e);
+            }
+        }
+        if let Some(cpus) = limits.cpu_quota {
+            self.write("cpu.max", &cpu_max_value(cpus))?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        let path = self.path.join(file);
+        std::fs::write(&path, value).map_err(|e| with_path(e, &path))
+    }
+
+    /// 返回供 `pre_exec` 使用的闭包：子进程在 exec 前把自己写入 `cgroup.procs`
+    ///
+    /// 闭包在 fork 后运行，只调用 async-signal-safe 的 open/write/close。
+    pub fn join_hook(&self) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+        let procs_path = self.procs_path.clone();
+        move || unsafe {
+            let fd = libc::open(procs_path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+            let write_error = io::Error::last_os_error();
+            libc::close(fd);
+            if written == 1 {
+                Ok(())
+            } else {
+                Err(write_error)
+            }
+        }
+    }
+
+    /// 杀死 cgroup 中的全部进程（包括脱离进程组的后台进程），需要 Linux 5.14+
+    pub fn kill(&self) {
+        if let Err(e) = self.write("cgroup.kill", "1") {
+            debug!("Failed to kill processes in {:?}: {}", self.path, e);
+        }
+    }
+
+    /// 因超出 `memory.max` 被 OOM killer 杀死的进程数对应的说明，未发生 OOM 时为 `None`
+    pub fn oom_note(&self) -> Option<String> {
+        let events = std::fs::read_to_string(self.path.join("memory.events")).ok()?;
+        let kills = parse_oom_kills(&events);
+        (kills > 0).then(|| {
+            format!(
+                "out of memory: {} process(es) killed at task_memory_limit of {} bytes",
+                kills,
+                self.memory_max.unwrap_or_default()
+            )
+        })
+    }
+
+    /// 删除 cgroup；其中仍有进程时短暂等待，超时后保留目录并记录警告
+    pub async fn remove(self) {
+        for _ in 0..REMOVE_RETRIES {
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    tokio::time::sleep(Duration::from_millis(REMOVE_RETRY_INTERVAL_MS)).await;
+                }
+                Err(e) => {
+                    warn!("Failed to remove cgroup {:?}: {}", self.path, e);
+                    return;
+                }
+            }
+        }
+        warn!(
+            "Cgroup {:?} still has running processes, leaving it in place",
+            self.path
+        );
+    }
+}
+
+/// 将 CPU 核数换算为 `cpu.max` 的 "<配额> <周期>"
+fn cpu_max_value(cpus: f64) -> String {
+    let quota = ((cpus * CPU_PERIOD_US as f64).round() as u64).max(MIN_CPU_QUOTA_US);
+    format!("{} {}", quota, CPU_PERIOD_US)
+}
+
+/// 从 `memory.events` 中读取 `oom_kill` 计数
+fn parse_oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn with_path(e: io::Error, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cpu_max_value, parse_oom_kills};
+
+    #[test]
+    fn cpu_max_value_converts_cores_to_quota() {
+        assert_eq!(cpu_max_value(1.5), "150000 100000");
+        assert_eq!(cpu_max_value(4.0), "400000 100000");
+        // 低于内核下限的配额提升到 1ms
+        assert_eq!(cpu_max_value(0.001), "1000 100000");
+    }
+
+    #[test]
+    fn parse_oom_kills_reads_memory_events() {
+        let events = "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), 1);
+        assert_eq!(parse_oom_kills("low 0\nhigh 0\n"), 0);
+    }
+}
@@ -0,0 +1,216 @@
+//! 诊断信息收集
+//!
+//! `tasknexus-agent doctor` 子命令的实现：汇总 Agent 版本、脱敏后的配置、shell 与 git 版本、
+//! 服务器连通性、工作空间所在磁盘的剩余空间以及开机自启状态，便于用户一次性提供给支持人员。
+
+use crate::client::AgentClient;
+use crate::config::{load_config, AgentConfig};
+use crate::executor::default_shell_path;
+use crate::resources::available_disk_bytes;
+use crate::service;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::time::{timeout, Duration};
+
+/// 服务器连通性测试的超时(秒)
+const SERVER_PROBE_TIMEOUT_SECS: u64 = 5;
+
+/// 服务器连通性测试结果
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerCheck {
+    pub server: String,
+    pub reachable: bool,
+    /// 建立连接耗时(毫秒)
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// 诊断报告
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    pub agent_version: String,
+    pub config_path: PathBuf,
+    /// 隐去密钥后的配置，加载失败时为空
+    pub config: Option<AgentConfig>,
+    pub config_errors: Vec<String>,
+    pub config_warnings: Vec<String>,
+    pub shell: String,
+    /// 配置的 `git_binary --version` 的输出，找不到 git 时为空
+    pub git_version: Option<String>,
+    /// 配置加载失败时不测试
+    pub server: Option<ServerCheck>,
+    pub workspaces_free_disk_bytes: Option<u64>,
+    pub autostart: String,
+}
+
+impl DoctorReport {
+    /// 收集诊断信息，任何一项失败都只记录在报告中，不会中断收集
+    pub async fn collect(config_path: PathBuf, service_name: &str) -> Self {
+        let (config, config_errors, config_warnings) = match load_config(config_path.clone()) {
+            Ok(config) => {
+                let errors = config.validate().err().unwrap_or_default();
+                let warnings = config.warnings();
+                (Some(config), errors, warnings)
+            }
+            Err(e) => (None, vec![e.to_string()], Vec::new()),
+        };
+
+        let server = match config {
+            Some(ref config) => Some(probe_server(config).await),
+            None => None,
+        };
+        let workspaces_free_disk_bytes = config
+            .as_ref()
+            .and_then(|config| available_disk_bytes(&config.workspaces_path));
+        let git_version = git_version(
+            config
+                .as_ref()
+                .map_or("git", |config| config.git_binary.as_str()),
+        );
+        let shell = match config {
+            Some(ref config) if cfg!(windows) => config.windows_shell.program(),
+            _ => default_shell_path(),
+        };
+
+        Self {
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_path,
+            config: config.map(|config| config.redacted()),
+            config_errors,
+            config_warnings,
+            shell: shell.to_string(),
+            git_version,
+            server,
+            workspaces_free_disk_bytes,
+            autostart: service::autostart_status(service_name),
+        }
+    }
+
+    /// 渲染为便于阅读的文本
+    pub fn render_text(&self) -> String {
+        let mut lines = vec![
+            format!("Agent 版本: {}", self.agent_version),
+            format!("配置文件: {}", self.config_path.display()),
+        ];
+        for error in &self.config_errors {
+            lines.push(format!("配置错误: {}", error));
+        }
+        for warning in &self.config_warnings {
+            lines.push(format!("配置警告: {}", warning));
+        }
+        lines.push(format!("Shell: {}", self.shell));
+        lines.push(format!(
+            "Git: {}",
+            self.git_version.as_deref().unwrap_or("未找到")
+        ));
+        if let Some(ref server) = self.server {
+            let status = match (server.reachable, server.latency_ms, &server.error) {
+                (true, Some(latency_ms), _) => format!("可达 ({} ms)", latency_ms),
+                (_, _, Some(error)) => format!("不可达 ({})", error),
+                _ => "不可达".to_string(),
+            };
+            lines.push(format!("服务器: {} {}", server.server, status));
+        }
+        lines.push(format!(
+            "工作空间剩余磁盘: {}",
+            self.workspaces_free_disk_bytes
+                .map(|bytes| format!("{} 字节", bytes))
+                .unwrap_or_else(|| "未知".to_string())
+        ));
+        lines.push(format!("开机自启: {}", self.autostart));
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        if let Some(ref config) = self.config {
+            text.push_str("\n解析后的配置（已隐去密钥）:\n");
+            text.push_str(&serde_yaml::to_string(config).unwrap_or_default());
+        }
+        text
+    }
+}
+
+/// 按正式连接的路径（含代理与 Unix socket）建立连接，测试服务器是否可达
+async fn probe_server(config: &AgentConfig) -> ServerCheck {
+    let client = AgentClient::new(config.clone());
+    let started = Instant::now();
+    let result = timeout(
+        Duration::from_secs(SERVER_PROBE_TIMEOUT_SECS),
+        client.probe_server(),
+    )
+    .await;
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("timed out after {}s", SERVER_PROBE_TIMEOUT_SECS)),
+    };
+    ServerCheck {
+        server: config.server.clone(),
+        reachable: error.is_none(),
+        latency_ms: error
+            .is_none()
+            .then(|| started.elapsed().as_millis() as u64),
+        error,
+    }
+}
+
+fn git_version(git_binary: &str) -> Option<String> {
+    let output = std::process::Command::new(git_binary)
+        .arg("--version")
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DoctorReport;
+    use std::fs;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn collect_reports_missing_config_without_probing_server() {
+        let path = std::env::temp_dir().join("tasknexus_doctor_missing.yaml");
+        let _ = fs::remove_file(&path);
+
+        let report = DoctorReport::collect(path, "tasknexus-doctor-test").await;
+
+        assert!(report.config.is_none());
+        assert!(report.server.is_none());
+        assert_eq!(report.config_errors.len(), 1);
+        assert!(report.render_text().contains("配置错误"));
+    }
+
+    #[tokio::test]
+    async fn collect_probes_server_and_redacts_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!(
+            "tasknexus_doctor_{}_{}.yaml",
+            std::process::id(),
+            port
+        ));
+        fs::write(
+            &path,
+            format!(
+                "server: ws://127.0.0.1:{}\nauth_token: top-secret\nworkspaces_path: {}\n",
+                port,
+                std::env::temp_dir().display()
+            ),
+        )
+        .unwrap();
+
+        let report = DoctorReport::collect(path.clone(), "tasknexus-doctor-test").await;
+        let _ = fs::remove_file(&path);
+
+        let server = report.server.as_ref().unwrap();
+        assert!(server.reachable, "error: {:?}", server.error);
+        assert!(server.latency_ms.is_some());
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(!json.contains("top-secret"));
+        assert!(!report.render_text().contains("top-secret"));
+    }
+}
@@ -14,8 +14,13 @@ pub enum AgentError {
     #[error("连接错误: {0}")]
     Connection(String),
 
+    /// 发送队列持续已满（连接仍在），调用方可稍后重试；断线时返回 [`AgentError::Connection`]
+    #[error("发送队列已满: {0}")]
+    QueueFull(String),
+
+    /// 装箱以避免 tungstenite 错误撑大所有 `Result` 的 `Err` 变体
     #[error("WebSocket 错误: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error("JSON 解析错误: {0}")]
     Json(#[from] serde_json::Error),
@@ -23,6 +28,9 @@ pub enum AgentError {
     #[error("YAML 解析错误: {0}")]
     Yaml(#[from] serde_yaml::Error),
 
+    #[error("TOML 解析错误: {0}")]
+    Toml(#[from] toml::de::Error),
+
     #[error("IO 错误: {0}")]
     Io(#[from] io::Error),
 
@@ -36,4 +44,10 @@ pub enum AgentError {
     Timeout,
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for AgentError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        AgentError::WebSocket(Box::new(err))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, AgentError>;
@@ -0,0 +1,118 @@
+//! 任务生命周期事件
+//!
+//! 以库方式嵌入 Agent 时，可通过 [`TaskRunner::with_event_sink`](crate::executor::TaskRunner::with_event_sink)
+//! 订阅任务状态变化，无需解析日志。事件流是 WebSocket 消息之外的补充，不影响与服务器的通信。
+//!
+//! 顺序保证：
+//! - 同一任务的事件按发生顺序送达：`Dispatched` 最先，`Completed` / `Failed` / `Cancelled` 三者之一最后；
+//! - 该任务的所有 `Progress` 都在终结事件之前送达，终结事件之后不会再出现该任务的 `Progress`
+//!   （超时或取消后进程仍在产生的输出会被丢弃）；
+//! - 不同任务的事件可能交错。
+//!
+//! 事件以 `send().await` 发送，sink 已满时任务会等待消费者；接收端关闭后事件被静默丢弃。
+
+use crate::executor::ExecutionResult;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// 任务生命周期事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskEvent {
+    /// 任务交给 TaskRunner，尚未准备仓库或执行
+    Dispatched {
+        task_id: i64,
+        workspace: String,
+        execution_mode: String,
+    },
+    /// 任务仓库已 clone 或更新完成
+    RepoCloned { task_id: i64, repo_path: PathBuf },
+    /// 命令已解析，即将启动进程
+    Started {
+        task_id: i64,
+        command: String,
+        working_dir: PathBuf,
+    },
+    /// 任务输出片段（包括 git 操作的输出）
+    Progress {
+        task_id: i64,
+        chunk: String,
+        is_stderr: bool,
+    },
+    /// 任务以退出码 0 结束
+    Completed { task_id: i64, exit_code: i32 },
+    /// 任务失败（包括超时）
+    Failed {
+        task_id: i64,
+        exit_code: i32,
+        timed_out: bool,
+        stderr: String,
+    },
+    /// 任务被取消
+    Cancelled { task_id: i64 },
+}
+
+/// 单个任务的事件发送端，负责维持 `Progress` 与终结事件之间的顺序
+#[derive(Clone)]
+pub(crate) struct TaskEventEmitter {
+    task_id: i64,
+    sink: Option<mpsc::Sender<TaskEvent>>,
+    finished: Arc<Mutex<bool>>,
+}
+
+impl TaskEventEmitter {
+    pub(crate) fn new(task_id: i64, sink: Option<mpsc::Sender<TaskEvent>>) -> Self {
+        Self {
+            task_id,
+            sink,
+            finished: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    pub(crate) async fn emit(&self, event: TaskEvent) {
+        if let Some(ref sink) = self.sink {
+            let _ = sink.send(event).await;
+        }
+    }
+
+    pub(crate) async fn progress(&self, chunk: String, is_stderr: bool) {
+        if self.sink.is_none() {
+            return;
+        }
+        let finished = self.finished.lock().await;
+        if !*finished {
+            self.emit(TaskEvent::Progress {
+                task_id: self.task_id,
+                chunk,
+                is_stderr,
+            })
+            .await;
+        }
+    }
+
+    /// 发送终结事件，之后到达的 `Progress` 被丢弃
+    pub(crate) async fn finish(&self, result: &ExecutionResult) {
+        if self.sink.is_none() {
+            return;
+        }
+        let mut finished = self.finished.lock().await;
+        *finished = true;
+        let task_id = self.task_id;
+        let event = if result.cancelled {
+            TaskEvent::Cancelled { task_id }
+        } else if result.exit_code == 0 && !result.timed_out {
+            TaskEvent::Completed {
+                task_id,
+                exit_code: result.exit_code,
+            }
+        } else {
+            TaskEvent::Failed {
+                task_id,
+                exit_code: result.exit_code,
+                timed_out: result.timed_out,
+                stderr: result.stderr.clone(),
+            }
+        };
+        self.emit(event).await;
+    }
+}
@@ -0,0 +1,107 @@
+//! 存活/就绪探针
+//!
+//! 与服务器保持连接时 `/healthz` 返回 200，断开时返回 503，供 Kubernetes 等编排系统重启卡住的 Agent；
+//! HTTP 端点需要启用 `health` feature，在配置了 `health_addr` 时由 `main` 启动。
+
+use crate::client::AgentClient;
+use serde::Serialize;
+
+/// `/healthz` 的响应体
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    /// 是否已连接到服务器
+    pub connected: bool,
+    pub uptime_secs: u64,
+    pub running_tasks: usize,
+}
+
+impl HealthStatus {
+    pub async fn collect(client: &AgentClient) -> Self {
+        Self {
+            connected: client.is_connected().await,
+            uptime_secs: client.uptime().as_secs(),
+            running_tasks: client.running_task_count().await,
+        }
+    }
+
+    /// 已连接为 200，否则为 503
+    pub fn http_status(&self) -> u16 {
+        if self.connected {
+            200
+        } else {
+            503
+        }
+    }
+}
+
+/// 在 `listener` 上提供 `GET /healthz`，其余路径返回 404；只在进程退出时返回
+#[cfg(feature = "health")]
+pub async fn serve(listener: tokio::net::TcpListener, client: AgentClient) {
+    use crate::http_server::HttpResponse;
+
+    crate::http_server::serve(listener, move |method, path| {
+        let client = client.clone();
+        async move {
+            if method != hyper::Method::GET || path != "/healthz" {
+                return HttpResponse::not_found();
+            }
+            let status = HealthStatus::collect(&client).await;
+            HttpResponse {
+                status: status.http_status(),
+                content_type: "application/json",
+                body: serde_json::to_string(&status).unwrap_or_default(),
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HealthStatus;
+    use crate::client::AgentClient;
+    use crate::config::AgentConfig;
+
+    #[tokio::test]
+    async fn health_is_unavailable_until_connected() {
+        let client = AgentClient::new(AgentConfig::default());
+        client.set_task_running(3, true).await;
+
+        let status = HealthStatus::collect(&client).await;
+        assert!(!status.connected);
+        assert_eq!(status.http_status(), 503);
+        assert_eq!(status.running_tasks, 1);
+        let body = serde_json::to_value(&status).unwrap();
+        assert_eq!(body["connected"], false);
+        assert!(body["uptime_secs"].is_u64());
+    }
+
+    #[cfg(feature = "health")]
+    #[tokio::test]
+    async fn serve_answers_healthz_with_503_while_disconnected() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(super::serve(
+            listener,
+            AgentClient::new(AgentConfig::default()),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\"connected\":false"), "{}", response);
+        server.abort();
+    }
+}
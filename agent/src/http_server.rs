@@ -0,0 +1,71 @@
+//! 本地 HTTP 端点（`/metrics`、`/healthz`）共用的最小 HTTP/1 服务
+//!
+//! 只在启用 `metrics` 或 `health` feature 时编译。
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+/// 端点处理函数的返回值
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn not_found() -> Self {
+        Self {
+            status: 404,
+            content_type: "text/plain",
+            body: "not found\n".to_string(),
+        }
+    }
+}
+
+/// 在 `listener` 上逐连接调用 `handler(method, path)`；只在进程退出时返回
+pub async fn serve<H, Fut>(listener: TcpListener, handler: H)
+where
+    H: Fn(Method, String) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request: Request<Incoming>| {
+                let response = handler(request.method().clone(), request.uri().path().to_string());
+                async move {
+                    let response = response.await;
+                    Response::builder()
+                        .status(
+                            StatusCode::from_u16(response.status)
+                                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                        )
+                        .header(CONTENT_TYPE, response.content_type)
+                        .body(Full::new(Bytes::from(response.body)))
+                }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("HTTP connection closed with error: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,70 @@
+//! 正在处理的任务分发
+//!
+//! 从收到 `task_dispatch` 到上报任务结果的整个过程中登记任务 ID。服务器因网络重试重复下发
+//! 同一任务时据此忽略重复的分发，而不是在工作空间占用、磁盘空间等前置检查中为仍在运行的任务上报失败。
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// 正在处理的任务 ID 集合
+#[derive(Debug, Clone, Default)]
+pub struct InflightTasks {
+    task_ids: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl InflightTasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记任务开始处理；该任务已在处理中时返回 `None`
+    pub fn try_begin(&self, task_id: i64) -> Option<InflightGuard> {
+        self.lock().insert(task_id).then(|| InflightGuard {
+            tasks: self.clone(),
+            task_id,
+        })
+    }
+
+    pub fn contains(&self, task_id: i64) -> bool {
+        self.lock().contains(&task_id)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<i64>> {
+        self.task_ids.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 任务处理结束（包括提前返回）时解除登记
+#[derive(Debug)]
+pub struct InflightGuard {
+    tasks: InflightTasks,
+    task_id: i64,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.tasks.lock().remove(&self.task_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InflightTasks;
+
+    #[test]
+    fn duplicate_dispatch_of_same_task_is_rejected_until_finished() {
+        let tasks = InflightTasks::new();
+
+        let first = tasks.try_begin(7).expect("first dispatch is accepted");
+        assert!(tasks.contains(7));
+        assert!(tasks.try_begin(7).is_none());
+        // 其他任务不受影响
+        let other = tasks.try_begin(8).expect("different task is accepted");
+
+        drop(first);
+        assert!(!tasks.contains(7));
+        assert!(tasks.contains(8));
+        assert!(tasks.try_begin(7).is_some());
+        drop(other);
+    }
+}
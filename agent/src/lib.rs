@@ -2,15 +2,30 @@
 //!
 //! 提供客户端代理的核心功能模块。
 
+pub mod at_rest;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 pub mod client;
 pub mod config;
+pub mod doctor;
 pub mod error;
+pub mod events;
 pub mod executor;
+pub mod health;
+#[cfg(any(feature = "metrics", feature = "health"))]
+pub mod http_server;
+pub mod inflight;
+pub mod metrics;
 pub mod persisted_state;
+pub mod resources;
+pub mod runtime_history;
 pub mod self_update;
 pub mod service;
+pub mod task_log;
+pub mod tls;
 
 pub use client::AgentClient;
 pub use config::AgentConfig;
 pub use error::{AgentError, Result};
-pub use executor::{CommandExecutor, ExecutionResult, TaskRunner};
+pub use events::TaskEvent;
+pub use executor::{CommandExecutor, ExecutionResult, TaskRunner, TaskSpec};
@@ -4,34 +4,52 @@
 
 use chrono::Local;
 use clap::Parser;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::{self, File};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::sync::Arc;
-use tokio::sync::{watch, Mutex, RwLock};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
 use tokio::time::{interval, Duration, Instant};
-use tracing::{error, info, warn, Level};
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing::{error, info, warn, Instrument, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{fmt, reload, EnvFilter};
 
 use tasknexus_agent::{
+    at_rest::{read_log_at, AtRestCipher, LogCipher, LogFileWriter},
     client::{
-        AgentClient, AgentRestartData, AgentUpdateData, StateSyncAction, StateSyncPayload,
-        StateSyncTask, TaskDispatchData, TaskStateAckData,
+        output_stream_name, AgentClient, AgentRestartData, AgentUpdateData, LogLevelSetter,
+        StateSyncAction, StateSyncPayload, StateSyncTask, TaskCompletion, TaskDispatchData,
+        TaskStateAckData,
     },
-    config::{load_config, AgentConfig},
-    executor::TaskRunner,
+    config::{load_config, AgentConfig, LogFormat, RunMode},
+    doctor::DoctorReport,
+    executor::{
+        validate_workspace_name, ExecutionResult, ShellOverride, TaskRunner, TaskRunnerOptions,
+        TaskSpec,
+    },
+    inflight::InflightTasks,
+    metrics::AgentMetrics,
     persisted_state::PersistedStateStore,
-    self_update,
-    service,
+    resources::{check_free_disk, ResourceBudget, ResourceRequest},
+    runtime_history::{task_signature, RuntimeHistoryStore},
+    self_update, service,
 };
 
 const MAX_LOG_CHUNK_BYTES: usize = 64 * 1024;
+/// 重启前未结束的任务上报失败时使用的错误信息
+const AGENT_RESTARTED_ERROR: &str = "agent restarted while the task was running";
 const LOG_APPEND_INTERVAL_MS: u64 = 200;
 const LOG_SYNC_INTERVAL_MS: u64 = 25;
 const LOG_ACTIVE_DEBOUNCE_MS: u64 = 125;
 const LOG_APPEND_ACK_TIMEOUT_MS: u64 = 1000;
 const LOG_FINAL_SYNC_TIMEOUT_MS: u64 = 5000;
+/// `progress_backpressure` 开启时允许的未确认日志字节数，超过后暂停读取任务输出
+const PROGRESS_BACKPRESSURE_MAX_UNACKED_BYTES: u64 = 1024 * 1024;
+/// `progress_backpressure` 下等待服务器确认的最长时间(秒)，超时后该任务不再等待
+const PROGRESS_BACKPRESSURE_TIMEOUT_SECS: u64 = 30;
+const SHUTDOWN_POLL_INTERVAL_MS: u64 = 100;
 const MAX_STORED_OUTPUT_CHARS: usize = 16 * 1024;
 const TASK_LOG_DIR_NAME: &str = ".tasknexus_task_logs";
 
@@ -62,8 +80,9 @@ struct InflightAppend {
 
 struct TaskLogSyncState {
     task_id: i64,
+    tag_streams: bool,
     local_log_path: PathBuf,
-    writer: BufWriter<File>,
+    writer: LogFileWriter,
     committed_offset: u64,
     acked_offset: u64,
     line_timestamp: Option<String>,
@@ -75,19 +94,34 @@ struct TaskLogSyncState {
     next_active_seq: u64,
     last_append_flush: Instant,
     last_active_change: Option<Instant>,
+    /// 两次日志追加之间的最短间隔
+    append_interval: Duration,
+    /// 未发送的完整行达到该数量时立即发送 (0 表示仅按间隔和字节数发送)
+    max_batch_lines: usize,
+    lines_since_flush: usize,
+    /// 等待服务器确认超时过一次后，本任务剩余的输出不再等待
+    backpressure_expired: bool,
+    /// 开启 report_output_stream 时，本地日志中连续同一输出流的区间 (结束偏移量, is_stderr)
+    stream_runs: VecDeque<(u64, bool)>,
 }
 
 impl TaskLogSyncState {
-    fn new(workspaces_path: &PathBuf, task_id: i64) -> std::io::Result<Self> {
+    fn new(
+        workspaces_path: &PathBuf,
+        task_id: i64,
+        tag_streams: bool,
+        cipher: Option<&AtRestCipher>,
+    ) -> std::io::Result<Self> {
         let log_dir = workspaces_path.join(TASK_LOG_DIR_NAME);
         fs::create_dir_all(&log_dir)?;
         let local_log_path = log_dir.join(format!("task_{}.log", task_id));
-        let file = File::create(&local_log_path)?;
+        let writer = LogFileWriter::create(&local_log_path, cipher)?;
 
         Ok(Self {
             task_id,
+            tag_streams,
             local_log_path,
-            writer: BufWriter::new(file),
+            writer,
             committed_offset: 0,
             acked_offset: 0,
             line_timestamp: None,
@@ -99,9 +133,21 @@ impl TaskLogSyncState {
             next_active_seq: 0,
             last_append_flush: Instant::now(),
             last_active_change: None,
+            append_interval: Duration::from_millis(LOG_APPEND_INTERVAL_MS),
+            max_batch_lines: 0,
+            lines_since_flush: 0,
+            backpressure_expired: false,
+            stream_runs: VecDeque::new(),
         })
     }
 
+    /// 设置日志批量发送的间隔与行数上限
+    fn with_append_batching(mut self, interval: Duration, max_batch_lines: usize) -> Self {
+        self.append_interval = interval;
+        self.max_batch_lines = max_batch_lines;
+        self
+    }
+
     fn ingest_chunk(&mut self, chunk: &str, is_stderr: bool) -> std::io::Result<()> {
         for ch in chunk.chars() {
             match ch {
@@ -166,20 +212,26 @@ impl TaskLogSyncState {
         self.retry_inflight_append_if_needed(client).await?;
 
         let backlog = self.committed_offset.saturating_sub(self.acked_offset);
+        let batch_full = self.max_batch_lines > 0 && self.lines_since_flush >= self.max_batch_lines;
         let should_flush_append = backlog > 0
             && (force_append
+                || batch_full
                 || backlog >= MAX_LOG_CHUNK_BYTES as u64
-                || self.last_append_flush.elapsed()
-                    >= Duration::from_millis(LOG_APPEND_INTERVAL_MS));
+                || self.last_append_flush.elapsed() >= self.append_interval);
 
         if self.inflight_append.is_none() && should_flush_append {
             self.flush_local_log_writer()?;
-            let (content, byte_len) =
-                read_utf8_chunk(&self.local_log_path, self.acked_offset, MAX_LOG_CHUNK_BYTES)?;
+            let (max_bytes, is_stderr) = self.stream_run_at(self.acked_offset);
+            let (content, byte_len) = read_utf8_chunk(
+                &self.local_log_path,
+                self.writer.cipher(),
+                self.acked_offset,
+                max_bytes,
+            )?;
             if byte_len > 0 && !content.is_empty() {
                 let connection_generation = client.connection_generation();
                 client
-                    .send_task_log_append(self.task_id, self.acked_offset, content)
+                    .send_task_log_append(self.task_id, self.acked_offset, content, is_stderr)
                     .await?;
                 self.inflight_append = Some(InflightAppend {
                     start_offset: self.acked_offset,
@@ -188,6 +240,7 @@ impl TaskLogSyncState {
                     connection_generation,
                 });
                 self.last_append_flush = Instant::now();
+                self.lines_since_flush = 0;
             }
         }
 
@@ -249,7 +302,11 @@ impl TaskLogSyncState {
     fn build_visible_from_buffer(&self) -> Option<VisibleLine> {
         let timestamp = self.line_timestamp.as_ref()?;
         Some(VisibleLine {
-            text: format!("[{}] {}", timestamp, self.line_buffer),
+            text: format!(
+                "{}{}",
+                self.line_prefix(timestamp, self.line_is_stderr),
+                self.line_buffer
+            ),
             is_stderr: self.line_is_stderr,
         })
     }
@@ -260,20 +317,67 @@ impl TaskLogSyncState {
             .clone()
             .unwrap_or_else(|| Local::now().format("%Y-%m-%dT%H:%M:%S%.3f").to_string());
         VisibleLine {
-            text: format!("[{}] ", timestamp),
+            text: self.line_prefix(&timestamp, is_stderr),
             is_stderr,
         }
     }
 
+    /// 行前缀：时间戳，开启 report_output_stream 时附带输出流名称
+    fn line_prefix(&self, timestamp: &str, is_stderr: bool) -> String {
+        if self.tag_streams {
+            format!("[{}] [{}] ", timestamp, output_stream_name(is_stderr))
+        } else {
+            format!("[{}] ", timestamp)
+        }
+    }
+
     fn commit_visible_line(&mut self, visible: VisibleLine) -> std::io::Result<()> {
         let record = format!("{}\n", visible.text);
         let bytes = record.as_bytes();
         self.writer.write_all(bytes)?;
         self.committed_offset += bytes.len() as u64;
+        if self.tag_streams {
+            match self.stream_runs.back_mut() {
+                Some((end, is_stderr)) if *is_stderr == visible.is_stderr => {
+                    *end = self.committed_offset;
+                }
+                _ => {
+                    self.stream_runs
+                        .push_back((self.committed_offset, visible.is_stderr));
+                }
+            }
+        }
+        self.lines_since_flush += 1;
         self.update_active_display(None);
         Ok(())
     }
 
+    /// `offset` 所在的同一输出流区间：返回本次追加最多读取的字节数和输出流，未标注输出流时为 `None`
+    ///
+    /// 只查找不删除，重发同一区间时仍能得到相同的输出流；区间在服务器确认后由 [`Self::prune_stream_runs`] 删除。
+    fn stream_run_at(&self, offset: u64) -> (usize, Option<bool>) {
+        match self.stream_runs.iter().find(|&&(end, _)| end > offset) {
+            Some(&(end, is_stderr)) => (
+                MAX_LOG_CHUNK_BYTES.min((end - offset) as usize),
+                Some(is_stderr),
+            ),
+            None => (MAX_LOG_CHUNK_BYTES, None),
+        }
+    }
+
+    /// 删除已被服务器确认的输出流区间
+    ///
+    /// 一次追加不跨区间，未确认完的追加所在区间结束于确认偏移量之后，重发时仍能查到。
+    fn prune_stream_runs(&mut self) {
+        while self
+            .stream_runs
+            .front()
+            .is_some_and(|&(end, _)| end <= self.acked_offset)
+        {
+            self.stream_runs.pop_front();
+        }
+    }
+
     fn reset_line_state(&mut self) {
         self.line_timestamp = None;
         self.line_buffer.clear();
@@ -305,6 +409,7 @@ impl TaskLogSyncState {
         let acked_offset = client.get_task_log_ack(self.task_id).await;
         if acked_offset > self.acked_offset {
             self.acked_offset = acked_offset;
+            self.prune_stream_runs();
         }
 
         if self
@@ -347,13 +452,19 @@ impl TaskLogSyncState {
 
         self.flush_local_log_writer()?;
         let max_bytes = end_offset.saturating_sub(start_offset) as usize;
-        let (content, byte_len) = read_utf8_chunk(&self.local_log_path, start_offset, max_bytes)?;
+        let (content, byte_len) = read_utf8_chunk(
+            &self.local_log_path,
+            self.writer.cipher(),
+            start_offset,
+            max_bytes,
+        )?;
         if byte_len == 0 || content.is_empty() {
             return Ok(());
         }
 
+        let (_, is_stderr) = self.stream_run_at(start_offset);
         client
-            .send_task_log_append(self.task_id, start_offset, content)
+            .send_task_log_append(self.task_id, start_offset, content, is_stderr)
             .await?;
         if let Some(inflight) = self.inflight_append.as_mut() {
             inflight.sent_at = Instant::now();
@@ -362,6 +473,11 @@ impl TaskLogSyncState {
         Ok(())
     }
 
+    /// 已写入本地日志但服务器尚未确认的字节数
+    fn unacked_bytes(&self) -> u64 {
+        self.committed_offset.saturating_sub(self.acked_offset)
+    }
+
     fn is_fully_synced(&self) -> bool {
         self.acked_offset == self.committed_offset
             && self.inflight_append.is_none()
@@ -369,6 +485,51 @@ impl TaskLogSyncState {
     }
 }
 
+/// `run_mode: single` 时由任务结果得到进程退出码：超时为 124（与 `timeout` 命令一致），被取消为 130，
+/// 其余取映射后的退出码，无法表示为 0-255 时为 1
+fn process_exit_code(result: &ExecutionResult) -> i32 {
+    if result.timed_out {
+        124
+    } else if result.cancelled {
+        130
+    } else if (0..=255).contains(&result.exit_code) {
+        result.exit_code
+    } else {
+        1
+    }
+}
+
+/// 等待服务器确认的日志追上本地输出；与服务器断开时不再等待，输出继续写入本地日志稍后补发。
+/// 超时后本任务剩余的输出都不再等待，避免每段输出各阻塞一次 `timeout`
+async fn wait_for_log_backlog(
+    state: &Mutex<TaskLogSyncState>,
+    client: &AgentClient,
+    timeout: Duration,
+) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let unacked = {
+            let guard = state.lock().await;
+            if guard.backpressure_expired {
+                return;
+            }
+            guard.unacked_bytes()
+        };
+        if unacked <= PROGRESS_BACKPRESSURE_MAX_UNACKED_BYTES || !client.is_connected().await {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Server is {} bytes behind on task log, disabling backpressure for this task",
+                unacked
+            );
+            state.lock().await.backpressure_expired = true;
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(LOG_SYNC_INTERVAL_MS)).await;
+    }
+}
+
 fn trim_output_for_storage(content: String) -> String {
     let char_count = content.chars().count();
     if char_count <= MAX_STORED_OUTPUT_CHARS {
@@ -386,16 +547,12 @@ fn trim_output_for_storage(content: String) -> String {
 }
 
 fn read_utf8_chunk(
-    path: &PathBuf,
+    path: &Path,
+    cipher: Option<&LogCipher>,
     start_offset: u64,
     max_bytes: usize,
 ) -> std::io::Result<(String, usize)> {
-    let mut file = File::open(path)?;
-    file.seek(SeekFrom::Start(start_offset))?;
-
-    let mut raw = vec![0u8; max_bytes];
-    let bytes_read = file.read(&mut raw)?;
-    raw.truncate(bytes_read);
+    let raw = read_log_at(path, cipher, start_offset, max_bytes)?;
     if raw.is_empty() {
         return Ok((String::new(), 0));
     }
@@ -429,19 +586,43 @@ enum Cli {
         /// 服务名称（由 service install 自动设置，请勿手动指定）
         #[arg(long, hide = true, default_value = service::DEFAULT_SERVICE_NAME)]
         service_name: String,
+        /// 只输出任务将要执行的命令与 git 操作，不实际执行（覆盖配置中的 dry_run）
+        #[arg(long)]
+        dry_run: bool,
+        /// 执行完第一个任务后退出，以任务退出码作为进程退出码（覆盖配置中的 run_mode）
+        #[arg(long)]
+        once: bool,
     },
     /// 系统服务管理
     Service {
         #[command(subcommand)]
         action: service::ServiceAction,
     },
+    /// 校验配置文件后退出（失败时返回非零退出码）
+    Validate {
+        /// 配置文件路径
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+    /// 输出诊断信息（版本、脱敏配置、shell/git、服务器连通性、磁盘空间、自启状态）
+    Doctor {
+        /// 配置文件路径
+        #[arg(short, long)]
+        config: PathBuf,
+        /// 查询自启状态使用的服务名称
+        #[arg(short, long, default_value = service::DEFAULT_SERVICE_NAME)]
+        name: String,
+        /// 以 JSON 输出，便于工具处理
+        #[arg(long)]
+        json: bool,
+    },
 }
 
-/// 自定义日志文件写入器，支持 24 小时自动轮转。
+/// 自定义日志文件写入器，支持 24 小时及按大小自动轮转。
 ///
 /// - 启动时：将已有的 `agent.log` 归档为 `agent_{timestamp}.log`
-/// - 运行中：每次写入时检查是否已超过 24 小时，超过则归档并重建
-/// - 每次归档后自动清理旧的归档文件
+/// - 运行中：每次写入时检查是否已超过 24 小时或 `log_max_size_mb`，超过则归档并重建
+/// - 每次归档后只保留最新的 `log_max_files` 个归档文件
 struct RotatingAgentLog {
     dir: PathBuf,
     file_name: String,
@@ -452,10 +633,15 @@ struct RotatingAgentLog {
     file: File,
     created_at: std::time::Instant,
     max_age: std::time::Duration,
+    /// 当前日志文件大小，超过 `max_size` 时轮转
+    size: u64,
+    max_size: Option<u64>,
+    /// 保留的归档文件数
+    max_files: usize,
 }
 
 impl RotatingAgentLog {
-    fn new(log_path: &PathBuf) -> std::io::Result<Self> {
+    fn new(log_path: &Path, max_files: usize, max_size: Option<u64>) -> std::io::Result<Self> {
         let dir = log_path
             .parent()
             .unwrap_or(std::path::Path::new("."))
@@ -480,22 +666,18 @@ impl RotatingAgentLog {
 
         let full_path = dir.join(&file_name);
 
-        // 清理旧的归档文件
-        Self::cleanup_archives(&dir, &stem, &ext);
-
-        // 启动时：将已有的日志文件归档
+        // 启动时：将已有的日志文件归档，并清理超出保留数量的旧归档
         if full_path.exists() {
-            let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
-            let archive_name = format!("{}_{}.{}", stem, timestamp, ext);
-            let archive_path = dir.join(&archive_name);
-            let _ = fs::rename(&full_path, &archive_path);
+            let _ = fs::rename(&full_path, Self::archive_path(&dir, &stem, &ext));
         }
+        Self::cleanup_archives(&dir, &stem, &ext, max_files);
 
         // 创建新的日志文件
         let file = fs::OpenOptions::new()
             .create(true)
             .append(true)
             .open(&full_path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
 
         Ok(Self {
             dir,
@@ -505,55 +687,86 @@ impl RotatingAgentLog {
             file,
             created_at: std::time::Instant::now(),
             max_age: std::time::Duration::from_secs(24 * 60 * 60),
+            size,
+            max_size,
+            max_files,
         })
     }
 
-    /// 检查是否需要轮转，超过 24 小时则归档当前文件并创建新文件
-    fn rotate_if_needed(&mut self) -> std::io::Result<()> {
-        if self.created_at.elapsed() < self.max_age {
+    /// 检查是否需要轮转：超过 24 小时，或写入 `incoming` 字节后超过大小上限时，
+    /// 归档当前文件并创建新文件
+    fn rotate_if_needed(&mut self, incoming: usize) -> std::io::Result<()> {
+        let too_large = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + incoming as u64 > max);
+        if self.created_at.elapsed() < self.max_age && !too_large {
             return Ok(());
         }
 
         let current_path = self.dir.join(&self.file_name);
-        let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
-        let archive_name = format!("{}_{}.{}", self.stem, timestamp, self.ext);
-        let archive_path = self.dir.join(&archive_name);
-
         let _ = self.file.flush();
-
-        // 先清理旧归档，再归档当前文件，避免刚归档的文件被清理掉
-        Self::cleanup_archives(&self.dir, &self.stem, &self.ext);
-
-        let _ = fs::rename(&current_path, &archive_path);
+        let _ = fs::rename(
+            &current_path,
+            Self::archive_path(&self.dir, &self.stem, &self.ext),
+        );
+        Self::cleanup_archives(&self.dir, &self.stem, &self.ext, self.max_files);
 
         self.file = fs::OpenOptions::new()
             .create(true)
             .append(true)
             .open(&current_path)?;
         self.created_at = std::time::Instant::now();
+        self.size = 0;
 
         Ok(())
     }
 
-    /// 删除所有旧的归档文件（{stem}_{timestamp}.{ext}）
-    fn cleanup_archives(dir: &PathBuf, stem: &str, ext: &str) {
+    /// 归档文件路径 `{stem}_{timestamp}.{ext}`，同一秒内多次轮转时追加序号避免覆盖
+    fn archive_path(dir: &Path, stem: &str, ext: &str) -> PathBuf {
+        let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
+        let mut archive_path = dir.join(format!("{}_{}.{}", stem, timestamp, ext));
+        let mut sequence = 1;
+        while archive_path.exists() {
+            archive_path = dir.join(format!("{}_{}_{}.{}", stem, timestamp, sequence, ext));
+            sequence += 1;
+        }
+        archive_path
+    }
+
+    /// 按修改时间保留最新的 `keep` 个归档文件（{stem}_{timestamp}.{ext}），删除其余
+    fn cleanup_archives(dir: &Path, stem: &str, ext: &str, keep: usize) {
         let prefix = format!("{}_", stem);
         let suffix = format!(".{}", ext);
-        if let Ok(entries) = fs::read_dir(dir) {
-            for entry in entries.flatten() {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut archives: Vec<(std::time::SystemTime, String, PathBuf)> = entries
+            .flatten()
+            .filter_map(|entry| {
                 let name = entry.file_name().to_string_lossy().to_string();
-                if name.starts_with(&prefix) && name.ends_with(&suffix) {
-                    let _ = fs::remove_file(entry.path());
+                if !name.starts_with(&prefix) || !name.ends_with(&suffix) {
+                    return None;
                 }
-            }
+                let modified = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .unwrap_or(std::time::UNIX_EPOCH);
+                Some((modified, name, entry.path()))
+            })
+            .collect();
+        archives.sort_by(|a, b| (&b.0, &b.1).cmp(&(&a.0, &a.1)));
+        for (_, _, path) in archives.into_iter().skip(keep) {
+            let _ = fs::remove_file(path);
         }
     }
 }
 
 impl Write for RotatingAgentLog {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let _ = self.rotate_if_needed();
-        self.file.write(buf)
+        let _ = self.rotate_if_needed(buf.len());
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -561,76 +774,255 @@ impl Write for RotatingAgentLog {
     }
 }
 
+/// 解析日志级别名（不区分大小写）
+fn parse_log_level(log_level: &str) -> Option<Level> {
+    match log_level.trim().to_uppercase().as_str() {
+        "TRACE" => Some(Level::TRACE),
+        "DEBUG" => Some(Level::DEBUG),
+        "INFO" => Some(Level::INFO),
+        "WARN" | "WARNING" => Some(Level::WARN),
+        "ERROR" => Some(Level::ERROR),
+        _ => None,
+    }
+}
+
+/// 构建日志过滤器，`RUST_LOG` 中的指令同样生效
+fn log_filter(level: Level) -> EnvFilter {
+    EnvFilter::from_default_env()
+        .add_directive(level.into())
+        .add_directive("tokio_tungstenite=warn".parse().unwrap())
+        .add_directive("tungstenite=warn".parse().unwrap())
+}
+
+/// 把日志过滤器的重载句柄包装为 [`LogLevelSetter`]，供服务器的 `set_log_level` 指令使用
+fn log_level_setter<S: 'static>(handle: reload::Handle<EnvFilter, S>) -> LogLevelSetter {
+    Arc::new(move |log_level: &str| {
+        let level = parse_log_level(log_level)
+            .ok_or_else(|| format!("unknown log level '{}'", log_level))?;
+        handle.reload(log_filter(level)).map_err(|e| e.to_string())
+    })
+}
+
 /// 配置日志
 ///
 /// 返回的 `WorkerGuard` 必须在程序生命周期内保持存活，
 /// 否则 non-blocking writer 会被 drop，文件日志停止写入。
-fn setup_logging(log_level: &str, log_file: Option<&PathBuf>) -> Option<tracing_appender::non_blocking::WorkerGuard> {
-    let level = match log_level.to_uppercase().as_str() {
-        "TRACE" => Level::TRACE,
-        "DEBUG" => Level::DEBUG,
-        "INFO" => Level::INFO,
-        "WARN" | "WARNING" => Level::WARN,
-        "ERROR" => Level::ERROR,
-        _ => Level::INFO,
-    };
-
-    let filter = EnvFilter::from_default_env()
-        .add_directive(level.into())
-        .add_directive("tokio_tungstenite=warn".parse().unwrap())
-        .add_directive("tungstenite=warn".parse().unwrap());
+fn setup_logging(
+    log_level: &str,
+    log_format: LogFormat,
+    log_file: Option<&PathBuf>,
+    log_max_files: usize,
+    log_max_size: Option<u64>,
+) -> (
+    Option<tracing_appender::non_blocking::WorkerGuard>,
+    LogLevelSetter,
+) {
+    let level = parse_log_level(log_level).unwrap_or(Level::INFO);
 
     let subscriber = fmt()
-        .with_env_filter(filter)
+        .with_env_filter(log_filter(level))
         .with_target(false)
         .with_thread_ids(false)
         .with_file(false)
         .with_line_number(false);
 
-    if let Some(log_path) = log_file {
-        let rotating_log = match RotatingAgentLog::new(log_path) {
-            Ok(log) => log,
-            Err(e) => {
-                eprintln!("Failed to initialize log file: {}", e);
-                subscriber.init();
-                return None;
-            }
-        };
-        let (non_blocking, guard) = tracing_appender::non_blocking(rotating_log);
-        subscriber.with_writer(non_blocking).init();
-        Some(guard)
-    } else {
-        subscriber.init();
-        None
+    let rotating_log =
+        log_file.map(|path| RotatingAgentLog::new(path, log_max_files, log_max_size));
+    let (writer, guard) = match rotating_log {
+        Some(Ok(rotating_log)) => {
+            let (non_blocking, guard) = tracing_appender::non_blocking(rotating_log);
+            (BoxMakeWriter::new(non_blocking), Some(guard))
+        }
+        Some(Err(e)) => {
+            eprintln!("Failed to initialize log file: {}", e);
+            (BoxMakeWriter::new(std::io::stdout), None)
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let setter = match log_format {
+        LogFormat::Text => {
+            let subscriber = subscriber.with_writer(writer).with_filter_reloading();
+            let setter = log_level_setter(subscriber.reload_handle());
+            subscriber.init();
+            setter
+        }
+        // 任务日志所在 span 的字段（task_id）放在 "span" 对象中
+        LogFormat::Json => {
+            let subscriber = subscriber
+                .json()
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_writer(writer)
+                .with_filter_reloading();
+            let setter = log_level_setter(subscriber.reload_handle());
+            subscriber.init();
+            setter
+        }
+    };
+    (guard, setter)
+}
+
+/// 任务被取消的原因，随 `task_cancelled` 上报
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CancelReason {
+    /// 服务器下发取消指令（task_cancel 消息或状态同步的 cancel 动作）
+    ServerRequested,
+    /// 服务器确认任务已处于终态，本地仍在运行的任务随之停止
+    ServerTerminalState,
+    /// Agent 收到退出信号，取消运行中的任务
+    AgentShutdown,
+    /// 没有服务器指令的取消，例如 Agent 退出导致取消信号通道关闭
+    AgentAborted,
+}
+
+impl CancelReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            CancelReason::ServerRequested => "server_requested",
+            CancelReason::ServerTerminalState => "server_terminal_state",
+            CancelReason::AgentShutdown => "agent_shutdown",
+            CancelReason::AgentAborted => "agent_aborted",
+        }
     }
 }
 
+/// 运行中任务的本地记录
+struct RunningTask {
+    workspace_name: String,
+    cancel_tx: watch::Sender<bool>,
+    /// 发出取消信号时记录的原因
+    cancel_reason: Option<CancelReason>,
+    local_log_path: PathBuf,
+    resources: ResourceRequest,
+    /// 进程已退出、终态消息尚未入队（断线后等待重连补发日志）；不占用并发槽位和资源预留
+    result_pending: bool,
+}
+
 /// Agent 主结构
 struct Agent {
     config: AgentConfig,
     task_runner: TaskRunner,
     client: AgentClient,
-    /// Maps task_id -> (workspace_name, cancel_sender, local_log_path)
-    running_tasks: Arc<RwLock<HashMap<i64, (String, watch::Sender<bool>, PathBuf)>>>,
+    /// Maps task_id -> running task record
+    running_tasks: Arc<RwLock<HashMap<i64, RunningTask>>>,
+    /// 从收到分发到上报结果期间的任务，重复下发的同一任务被忽略
+    inflight_tasks: InflightTasks,
+    resource_budget: Arc<Mutex<ResourceBudget>>,
+    runtime_history: Arc<Mutex<RuntimeHistoryStore>>,
     persisted_state: Arc<Mutex<PersistedStateStore>>,
+    /// 配置 `at_rest_encryption_key` 时用于加密任务日志
+    at_rest_cipher: Option<AtRestCipher>,
     update_in_progress: Arc<RwLock<bool>>,
+    /// 收到退出信号后置位，不再接收新任务
+    shutting_down: Arc<RwLock<bool>>,
+    /// 启动时按 CPU 核数解析的任务总数上限 (0 表示不限制)
+    max_total_tasks: usize,
+    /// 任务与连接指标，配置 `metrics_addr` 时通过 /metrics 暴露
+    metrics: Arc<AgentMetrics>,
+    /// `run_mode: single` 时已接收过任务，之后的分发一律拒绝
+    single_task_accepted: AtomicBool,
+    /// `run_mode: single` 时任务结束后的进程退出码
+    single_task_exit_code: AtomicI32,
+    /// `run_mode: single` 时任务结束，触发优雅退出
+    single_task_finished: Notify,
 }
 
 impl Agent {
     fn new(
         config: AgentConfig,
         persisted_state: PersistedStateStore,
+        at_rest_cipher: Option<AtRestCipher>,
+        log_level_setter: LogLevelSetter,
     ) -> Self {
-        let task_runner = TaskRunner::new(config.workspaces_path.clone(), config.proxy_env());
-        let client = AgentClient::new(config.clone());
+        // 规则只在启动时编译一次；run_agent 已校验过配置
+        let command_policy = config.command_policy().unwrap_or_else(|errors| {
+            error!("Invalid command policy: {}", errors.join("; "));
+            std::process::exit(1);
+        });
+        let task_runner = TaskRunner::with_options(
+            config.workspaces_path.clone(),
+            config.proxy_env(),
+            TaskRunnerOptions {
+                detect_default_branch: config.detect_default_branch,
+                verify_repo_integrity: config.verify_repo_integrity,
+                grace_period_secs: config.grace_period_secs,
+                clear_stale_git_locks: config.clear_stale_git_locks,
+                git_retries: config.git_retries,
+                git_retry_delay_secs: config.git_retry_delay_secs,
+                git_clone_timeout_secs: config.git_clone_timeout_secs,
+                task_log_dir: config.task_log_dir.clone(),
+                task_log_retention: config.task_log_retention,
+                at_rest_cipher: at_rest_cipher.clone(),
+                detect_shell_init_failure: config.detect_shell_init_failure,
+                shell_login_interactive: config.shell_login_interactive,
+                large_env_to_file_threshold: config.large_env_to_file_threshold,
+                env_clear: config.env_clear,
+                env_passthrough: config.env_passthrough.clone(),
+                dry_run: config.dry_run,
+                repo_cache_path: config.repo_cache_path.clone(),
+                output_encoding: config.output_encoding,
+                max_line_bytes: config.max_line_bytes,
+                command_policy,
+                default_timeout_secs: config.clamp_task_timeout(config.task_timeout),
+                workspace_env: config.workspaces.clone(),
+                workspace_cleanup: config.workspace_cleanup,
+                shell: ShellOverride {
+                    shell: config.shell.clone(),
+                    args: config.shell_args.clone(),
+                },
+                container_image: config.container_image.clone(),
+                container_user: config.effective_container_user(),
+                resource_limits: config.task_resource_limits(),
+                nice: config.task_nice,
+                git_binary: config.git_binary.clone(),
+                git_extra_args: config.git_extra_args.clone(),
+                command_wrapper: config.command_wrapper_args(),
+                windows_shell: config.windows_shell,
+                git_always_update: config.git_always_update,
+            },
+        );
+        let resource_budget = Arc::new(Mutex::new(ResourceBudget::detect(
+            config.cpu_capacity,
+            config.memory_capacity,
+        )));
+        let metrics = Arc::new(AgentMetrics::new());
+        let client = AgentClient::new(config.clone())
+            .with_resource_budget(resource_budget.clone())
+            .with_log_level_control(log_level_setter)
+            .with_metrics(metrics.clone());
+        let runtime_history =
+            RuntimeHistoryStore::load_with_cipher(&config.workspaces_path, at_rest_cipher.clone())
+                .unwrap_or_else(|e| {
+                    warn!("Failed to load runtime history, starting empty: {}", e);
+                    RuntimeHistoryStore::new(&config.workspaces_path)
+                        .with_cipher(at_rest_cipher.clone())
+                });
+        let max_total_tasks = config.effective_max_total_tasks();
+        if max_total_tasks > 0 {
+            info!(
+                "Max total tasks: {} (configured as {:?})",
+                max_total_tasks, config.max_total_tasks
+            );
+        }
 
         Self {
             config,
             task_runner,
             client,
             running_tasks: Arc::new(RwLock::new(HashMap::new())),
+            inflight_tasks: InflightTasks::new(),
+            resource_budget,
+            runtime_history: Arc::new(Mutex::new(runtime_history)),
             persisted_state: Arc::new(Mutex::new(persisted_state)),
+            at_rest_cipher,
             update_in_progress: Arc::new(RwLock::new(false)),
+            shutting_down: Arc::new(RwLock::new(false)),
+            max_total_tasks,
+            metrics,
+            single_task_accepted: AtomicBool::new(false),
+            single_task_exit_code: AtomicI32::new(1),
+            single_task_finished: Notify::new(),
         }
     }
 
@@ -704,16 +1096,16 @@ impl Agent {
                 .collect::<Vec<_>>()
         };
         let running = self.running_tasks.read().await;
-        for (task_id, (workspace_name, _, log_path)) in running.iter() {
+        for (task_id, running_task) in running.iter() {
             if tasks.iter().any(|task| task.task_id == *task_id) {
                 continue;
             }
             tasks.push(StateSyncTask {
                 task_id: *task_id,
                 local_state: "RUNNING".to_string(),
-                workspace_name: workspace_name.clone(),
+                workspace_name: running_task.workspace_name.clone(),
                 final_payload: serde_json::Value::Object(serde_json::Map::new()),
-                has_local_log: !log_path.as_os_str().is_empty(),
+                has_local_log: !running_task.local_log_path.as_os_str().is_empty(),
             });
         }
         tasks.sort_by_key(|task| task.task_id);
@@ -760,7 +1152,25 @@ impl Agent {
                                 environment,
                                 prepare_repo_before_execute,
                                 cleanup_workspace_on_success,
+                                cpu_request,
+                                memory_request,
+                                number_lines,
+                                exit_code_map,
+                                stdin,
+                                shell,
+                                shell_args,
+                                working_subdir,
+                                container_image,
+                                nice,
                             } => {
+                                // 重复下发正在处理的任务时不能清理其日志确认与持久化状态
+                                if self.inflight_tasks.contains(task_id) {
+                                    info!(
+                                        "Task {} is already being handled, ignore start action",
+                                        task_id
+                                    );
+                                    continue;
+                                }
                                 self.client.clear_task_log_ack(task_id).await;
                                 self.clear_persisted_task_state(task_id).await;
                                 self.handle_task_dispatch(TaskDispatchData {
@@ -776,14 +1186,34 @@ impl Agent {
                                     environment,
                                     prepare_repo_before_execute,
                                     cleanup_workspace_on_success,
+                                    cpu_request,
+                                    memory_request,
+                                    number_lines,
+                                    exit_code_map,
+                                    stdin,
+                                    shell: ShellOverride {
+                                        shell,
+                                        args: shell_args,
+                                    },
+                                    working_subdir,
+                                    container_image,
+                                    nice,
                                 })
                                 .await;
                             }
-                            StateSyncPayload::AgentUpdate { task_id, download_url } => {
-                                self.handle_agent_update(AgentUpdateData { task_id, download_url }).await;
+                            StateSyncPayload::AgentUpdate {
+                                task_id,
+                                download_url,
+                            } => {
+                                self.handle_agent_update(AgentUpdateData {
+                                    task_id,
+                                    download_url,
+                                })
+                                .await;
                             }
                             StateSyncPayload::AgentRestart { task_id } => {
-                                self.handle_agent_restart(AgentRestartData { task_id }).await;
+                                self.handle_agent_restart(AgentRestartData { task_id })
+                                    .await;
                             }
                         }
                     }
@@ -794,7 +1224,8 @@ impl Agent {
                         .await;
                 }
                 "cancel" => {
-                    self.handle_task_cancel(action.task_id).await;
+                    self.handle_task_cancel(action.task_id, CancelReason::ServerRequested)
+                        .await;
                     self.clear_persisted_task_state(action.task_id).await;
                 }
                 "clear" => {
@@ -829,7 +1260,8 @@ impl Agent {
             "COMPLETED" | "FAILED" | "CANCELLED" | "TIMEOUT"
         ) {
             if self.running_tasks.read().await.contains_key(&ack.task_id) {
-                self.handle_task_cancel(ack.task_id).await;
+                self.handle_task_cancel(ack.task_id, CancelReason::ServerTerminalState)
+                    .await;
             }
             self.clear_persisted_task_state(ack.task_id).await;
         }
@@ -849,7 +1281,8 @@ impl Agent {
         }
     }
 
-    async fn start(self) -> Result<(), Box<dyn std::error::Error>> {
+    /// 运行到客户端停止；`run_mode: single` 且执行过任务时返回进程应使用的退出码
+    async fn start(self) -> Result<Option<i32>, Box<dyn std::error::Error>> {
         info!("Starting TaskNexus Agent: {}", self.config.name);
         info!("Server: {}", self.config.server);
         info!("Workspaces path: {:?}", self.config.workspaces_path);
@@ -857,8 +1290,73 @@ impl Agent {
         // 确保工作目录存在
         std::fs::create_dir_all(&self.config.workspaces_path)?;
 
+        if let Some(addr) = self.config.metrics_addr {
+            self.start_metrics_server(addr).await?;
+        }
+        if let Some(addr) = self.config.health_addr {
+            self.start_health_server(addr).await?;
+        }
+
+        // 上次退出前未能删除的环境变量值文件可能包含密钥
+        self.task_runner.remove_leftover_env_files();
+
+        if let Some(error) = self.task_runner.probe_shell().await {
+            warn!(
+                "Default shell failed to initialize, tasks will fail until fixed: {}",
+                error.trim()
+            );
+        }
+
+        // 上次进程退出时仍在运行的任务（崩溃或被杀）上报失败，消息在连接建立后发送
+        let lost_tasks = {
+            let mut store = self.persisted_state.lock().await;
+            store.fail_lost_tasks(AGENT_RESTARTED_ERROR)
+        };
+        if !lost_tasks.is_empty() {
+            self.save_persisted_state().await;
+            for task_id in lost_tasks {
+                warn!(
+                    "Task {} was running when the agent stopped, reporting failure",
+                    task_id
+                );
+                if let Err(e) = self
+                    .client
+                    .send_task_failed(task_id, AGENT_RESTARTED_ERROR.to_string())
+                    .await
+                {
+                    warn!("Failed to queue failure for lost task {}: {}", task_id, e);
+                }
+            }
+        }
+
         let agent = Arc::new(self);
 
+        // 第一次信号优雅退出，等待期间再次收到信号则立即退出
+        let agent_shutdown = agent.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!(
+                "Shutdown signal received, draining running tasks (up to {}s)",
+                agent_shutdown.config.shutdown_grace_secs
+            );
+            tokio::select! {
+                _ = agent_shutdown.shutdown() => {}
+                _ = shutdown_signal() => {
+                    warn!("Second shutdown signal received, exiting immediately");
+                    std::process::exit(130);
+                }
+            }
+        });
+
+        if agent.config.run_mode == RunMode::Single {
+            let agent_single = agent.clone();
+            tokio::spawn(async move {
+                agent_single.single_task_finished.notified().await;
+                info!("Single task finished, waiting for the server to acknowledge its result");
+                agent_single.shutdown().await;
+            });
+        }
+
         let agent_clone = agent.clone();
         let agent_cancel = agent.clone();
         let agent_update = agent.clone();
@@ -879,7 +1377,9 @@ impl Agent {
                 move |task_id| {
                     let agent = agent_cancel.clone();
                     async move {
-                        agent.handle_task_cancel(task_id).await;
+                        agent
+                            .handle_task_cancel(task_id, CancelReason::ServerRequested)
+                            .await;
                     }
                 },
                 move |data| {
@@ -920,20 +1420,92 @@ impl Agent {
             )
             .await?;
 
+        let single_task_ran = agent.config.run_mode == RunMode::Single
+            && agent.single_task_accepted.load(Ordering::SeqCst);
+        Ok(single_task_ran.then(|| agent.single_task_exit_code.load(Ordering::SeqCst)))
+    }
+
+    /// 在 `addr` 上启动 /metrics 端点；端口无法绑定时启动失败
+    #[cfg(feature = "metrics")]
+    async fn start_metrics_server(
+        &self,
+        addr: std::net::SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Metrics endpoint listening on http://{}/metrics", addr);
+        tokio::spawn(tasknexus_agent::metrics::serve(
+            listener,
+            self.metrics.clone(),
+        ));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    async fn start_metrics_server(
+        &self,
+        addr: std::net::SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        warn!(
+            "metrics_addr {} is set but this build lacks the `metrics` feature; /metrics is disabled",
+            addr
+        );
+        Ok(())
+    }
+
+    /// 在 `addr` 上启动 /healthz 探针；端口无法绑定时启动失败
+    #[cfg(feature = "health")]
+    async fn start_health_server(
+        &self,
+        addr: std::net::SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Health endpoint listening on http://{}/healthz", addr);
+        tokio::spawn(tasknexus_agent::health::serve(
+            listener,
+            self.client.clone(),
+        ));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "health"))]
+    async fn start_health_server(
+        &self,
+        addr: std::net::SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        warn!(
+            "health_addr {} is set but this build lacks the `health` feature; /healthz is disabled",
+            addr
+        );
         Ok(())
     }
 
+    /// 处理任务分发；该任务的所有日志（包括其中启动的后台任务）都在带 `task_id`、`workspace` 字段的 span 中
+    #[tracing::instrument(
+        name = "task",
+        skip_all,
+        fields(task_id = data.task_id, workspace = %data.workspace_name)
+    )]
     async fn handle_task_dispatch(&self, data: TaskDispatchData) {
         let task_id = data.task_id;
+        // 服务器重试导致的重复分发：任务仍在处理中，不能再走前置检查为其上报失败
+        let Some(_inflight) = self.inflight_tasks.try_begin(task_id) else {
+            info!(
+                "Task {} is already being handled, ignore duplicate dispatch",
+                task_id
+            );
+            return;
+        };
         let workspace_name = data.workspace_name.clone();
         let execution_mode = data.execution_mode.clone();
         let command = data.command.clone();
+        self.metrics.record_task_dispatched();
 
         if *self.update_in_progress.read().await {
             warn!(
                 "Reject task {} because self-update is currently in progress",
                 task_id
             );
+            self.metrics.record_task_rejected();
             let _ = self
                 .client
                 .send_task_failed(task_id, "Agent is updating; task rejected".to_string())
@@ -941,6 +1513,30 @@ impl Agent {
             return;
         }
 
+        if let Err(reason) = validate_workspace_name(&workspace_name).and_then(|_| {
+            self.task_runner.check_command_policy(
+                &execution_mode,
+                &command,
+                data.code.as_ref(),
+                &data.shell,
+            )
+        }) {
+            warn!("Reject task {}: {}", task_id, reason);
+            self.metrics.record_task_rejected();
+            let _ = self.client.send_task_failed(task_id, reason).await;
+            return;
+        }
+
+        if let Err(reason) = check_free_disk(
+            &self.config.workspaces_path,
+            self.config.min_free_disk_bytes,
+        ) {
+            warn!("Reject task {}: {}", task_id, reason);
+            self.metrics.record_task_rejected();
+            let _ = self.client.send_task_failed(task_id, reason).await;
+            return;
+        }
+
         if execution_mode == "code" {
             let language = data
                 .code
@@ -958,26 +1554,37 @@ impl Agent {
             );
         }
 
-        // 防止重复分发同一个 task_id（例如重试场景）
+        // 创建取消信号通道
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let resources = ResourceRequest::new(data.cpu_request, data.memory_request);
+
+        // 并发检查、预留资源与登记运行状态在同一把写锁内完成；重复分发已由 inflight_tasks 排除
         {
-            let running = self.running_tasks.read().await;
-            if running.contains_key(&task_id) {
-                warn!(
-                    "Task {} is already running, ignore duplicate dispatch",
-                    task_id
-                );
+            let mut running = self.running_tasks.write().await;
+            let admission = self.admit_task(&running, &workspace_name, &resources).await;
+            if let Err(reason) = admission {
+                drop(running);
+                warn!("Reject task {}: {}", task_id, reason);
+                self.metrics.record_task_rejected();
+                let _ = self.client.send_task_failed(task_id, reason).await;
                 return;
             }
-        }
 
-        // 创建取消信号通道
-        let (cancel_tx, cancel_rx) = watch::channel(false);
-
-        // 标记任务正在运行
-        self.running_tasks
-            .write()
-            .await
-            .insert(task_id, (workspace_name.clone(), cancel_tx, PathBuf::new()));
+            // 标记任务正在运行
+            running.insert(
+                task_id,
+                RunningTask {
+                    workspace_name: workspace_name.clone(),
+                    cancel_tx,
+                    cancel_reason: None,
+                    local_log_path: PathBuf::new(),
+                    resources,
+                    result_pending: false,
+                },
+            );
+            // 在同一把锁内登记，心跳上报的 current_tasks 与并发检查保持一致
+            self.client.set_task_running(task_id, true).await;
+        }
 
         // 通知任务开始
         if let Err(e) = self.client.send_task_started(task_id).await {
@@ -985,27 +1592,34 @@ impl Agent {
         }
 
         self.client.clear_task_log_ack(task_id).await;
-        let log_sync_state = match TaskLogSyncState::new(&self.config.workspaces_path, task_id) {
-            Ok(state) => Arc::new(Mutex::new(state)),
+        let log_sync_state = match TaskLogSyncState::new(
+            &self.config.workspaces_path,
+            task_id,
+            self.config.report_output_stream,
+            self.at_rest_cipher.as_ref(),
+        ) {
+            Ok(state) => Arc::new(Mutex::new(state.with_append_batching(
+                Duration::from_millis(self.config.progress_batch_interval_ms),
+                self.config.progress_max_batch_lines,
+            ))),
             Err(e) => {
                 error!(
                     "Failed to initialize task log sync state for {}: {}",
                     task_id, e
                 );
+                self.metrics.record_task_rejected();
                 let _ = self
                     .client
                     .send_task_failed(task_id, format!("Failed to initialize task logs: {}", e))
                     .await;
-                self.running_tasks.write().await.remove(&task_id);
+                self.finish_running_task(task_id).await;
                 return;
             }
         };
         {
             let log_path = log_sync_state.lock().await.local_log_path.clone();
-            if let Some((_, _, existing_log_path)) =
-                self.running_tasks.write().await.get_mut(&task_id)
-            {
-                *existing_log_path = log_path.clone();
+            if let Some(running_task) = self.running_tasks.write().await.get_mut(&task_id) {
+                running_task.local_log_path = log_path.clone();
             }
             let mut store = self.persisted_state.lock().await;
             store.upsert_running(task_id, workspace_name.clone(), log_path);
@@ -1015,73 +1629,118 @@ impl Agent {
         // 启动任务心跳发送器
         let heartbeat_client = self.client.clone();
         let mut heartbeat_cancel_rx = cancel_rx.clone();
-        let heartbeat_task = tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(30));
-            loop {
-                tokio::select! {
-                    _ = interval.tick() => {
-                        if let Err(e) = heartbeat_client.send_task_heartbeat(task_id).await {
-                            warn!("Failed to send task heartbeat: {}", e);
+        let heartbeat_task = tokio::spawn(
+            async move {
+                let mut interval = interval(Duration::from_secs(30));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            if let Err(e) = heartbeat_client.send_task_heartbeat(task_id).await {
+                                warn!("Failed to send task heartbeat: {}", e);
+                            }
+                        }
+                        _ = heartbeat_cancel_rx.changed() => {
+                            break;
                         }
-                    }
-                    _ = heartbeat_cancel_rx.changed() => {
-                        break;
                     }
                 }
             }
-        });
+            .in_current_span(),
+        );
 
         let state_for_callback = log_sync_state.clone();
+        let backpressure_client = self
+            .config
+            .progress_backpressure
+            .then(|| self.client.clone());
         let output_callback = move |line: String, is_stderr: bool| {
             let state = state_for_callback.clone();
+            let backpressure_client = backpressure_client.clone();
             async move {
                 let mut guard = state.lock().await;
                 if let Err(e) = guard.ingest_chunk(&line, is_stderr) {
                     error!("Failed to buffer task log chunk: {}", e);
                 }
+                drop(guard);
+                // 回调返回前不会读取更多输出，任务随之在写满的管道上阻塞
+                if let Some(client) = backpressure_client {
+                    let timeout = Duration::from_secs(PROGRESS_BACKPRESSURE_TIMEOUT_SECS);
+                    wait_for_log_backlog(&state, &client, timeout).await;
+                }
             }
         };
 
         let flush_client = self.client.clone();
         let flush_state = log_sync_state.clone();
-        let log_flush_task = tokio::spawn(async move {
-            let mut tick = interval(Duration::from_millis(LOG_SYNC_INTERVAL_MS));
-            loop {
-                tick.tick().await;
-                let mut guard = flush_state.lock().await;
-                let _ = guard.sync_with_server(&flush_client, false, false).await;
+        let log_flush_task = tokio::spawn(
+            async move {
+                let mut tick = interval(Duration::from_millis(LOG_SYNC_INTERVAL_MS));
+                loop {
+                    tick.tick().await;
+                    let mut guard = flush_state.lock().await;
+                    let _ = guard.sync_with_server(&flush_client, false, false).await;
+                }
             }
-        });
+            .in_current_span(),
+        );
+
+        let signature = task_signature(
+            &data.workspace_name,
+            &data.execution_mode,
+            data.code
+                .as_ref()
+                .map(|code| code.content.as_str())
+                .unwrap_or(data.command.as_str()),
+        );
+        let timeout_secs = self.resolve_task_timeout(data.timeout, &signature).await;
+        let started_at = Instant::now();
 
         // 执行任务，传入取消信号
-        let result = self
+        let mut result = self
             .task_runner
             .run_task(
                 task_id,
-                &data.execution_mode,
-                &data.command,
-                data.code.as_ref(),
-                &data.workspace_name,
-                data.client_repo_url.as_deref(),
-                &data.client_repo_ref,
-                data.client_repo_token.as_deref(),
-                data.prepare_repo_before_execute,
-                data.cleanup_workspace_on_success,
-                data.timeout,
-                Some(output_callback),
-                if data.environment.is_empty() {
-                    None
-                } else {
-                    Some(data.environment)
+                TaskSpec {
+                    execution_mode: &data.execution_mode,
+                    command: &data.command,
+                    code: data.code.as_ref(),
+                    workspace_name: &data.workspace_name,
+                    client_repo_url: data.client_repo_url.as_deref(),
+                    client_repo_ref: &data.client_repo_ref,
+                    client_repo_token: data.client_repo_token.as_deref(),
+                    prepare_repo_before_execute: data.prepare_repo_before_execute,
+                    cleanup_workspace_on_success: data.cleanup_workspace_on_success,
+                    timeout_secs: Some(timeout_secs),
+                    environment: (!data.environment.is_empty()).then_some(data.environment),
+                    number_lines: data.number_lines,
+                    stdin: data.stdin,
+                    shell: data.shell,
+                    working_subdir: data.working_subdir.as_deref(),
+                    container_image: data.container_image.as_deref(),
+                    nice: data.nice,
                 },
+                Some(output_callback),
                 Some(cancel_rx),
             )
             .await;
+        let raw_exit_code = result.apply_exit_code_map(&data.exit_code_map);
+        if self.config.run_mode == RunMode::Single {
+            self.single_task_exit_code
+                .store(process_exit_code(&result), Ordering::SeqCst);
+        }
 
         // 停止日志批量发送和心跳
         log_flush_task.abort();
         heartbeat_task.abort();
 
+        if result.exit_code == 0 && !result.timed_out && !result.cancelled {
+            let mut history = self.runtime_history.lock().await;
+            history.record(&signature, started_at.elapsed().as_millis() as u64);
+            if let Err(e) = history.save() {
+                warn!("Failed to save runtime history: {}", e);
+            }
+        }
+
         let (local_log_flush_succeeded, local_log_path) = {
             let mut guard = log_sync_state.lock().await;
             let finalize_ok = if let Err(e) = guard.finalize_pending() {
@@ -1093,6 +1752,33 @@ impl Agent {
             let local_log_path = guard.local_log_path.clone();
             (finalize_ok, local_log_path)
         };
+        let cancel_reason = self
+            .running_tasks
+            .read()
+            .await
+            .get(&task_id)
+            .and_then(|running_task| running_task.cancel_reason)
+            .unwrap_or(CancelReason::AgentAborted);
+        // 断线期间产生的输出已落盘，等待重连后按偏移量补发，而不是直接放弃
+        if !self.client.is_connected().await {
+            // 进程已退出，等待重连期间不再占用并发槽位和资源预留
+            self.release_task_slot(task_id).await;
+            info!(
+                "Task {} finished while disconnected, waiting up to {}s for reconnect to flush logs",
+                task_id, self.config.offline_log_flush_timeout_secs
+            );
+            if !self
+                .wait_for_reconnect(Duration::from_secs(
+                    self.config.offline_log_flush_timeout_secs,
+                ))
+                .await
+            {
+                warn!(
+                    "Task {} did not reconnect before log flush timeout",
+                    task_id
+                );
+            }
+        }
         let fully_synced = self
             .flush_task_logs_until_synced(
                 &log_sync_state,
@@ -1101,10 +1787,22 @@ impl Agent {
             .await;
         let local_log_flush_succeeded = local_log_flush_succeeded && fully_synced;
 
+        // 进程未能启动属于 Agent 环境问题（如 shell 缺失），按任务失败上报而不是命令退出码
+        let spawn_error = result
+            .spawn_failed
+            .then(|| format!("Failed to start command: {}", result.stderr.trim()));
+
         {
             let mut store = self.persisted_state.lock().await;
             if result.cancelled {
                 store.remove(task_id);
+            } else if let Some(ref error) = spawn_error {
+                store.mark_failed(
+                    task_id,
+                    workspace_name.clone(),
+                    local_log_path.clone(),
+                    error.clone(),
+                );
             } else if result.exit_code == 0 {
                 store.mark_completed(
                     task_id,
@@ -1120,7 +1818,7 @@ impl Agent {
                     task_id,
                     workspace_name.clone(),
                     local_log_path.clone(),
-                    format!("Task timed out after {} seconds", data.timeout),
+                    format!("Task timed out after {} seconds", timeout_secs),
                 );
             } else {
                 store.mark_completed(
@@ -1135,22 +1833,38 @@ impl Agent {
             }
         }
         self.save_persisted_state().await;
+        self.metrics.record_task_finished(&result);
 
         // 发送结果
         if result.cancelled {
             info!(
-                "Task {} was cancelled, not sending completion (server already handled)",
-                task_id
+                "Task {} was cancelled ({})",
+                task_id,
+                cancel_reason.as_str()
             );
+            if let Err(e) = self
+                .client
+                .send_task_cancelled(task_id, cancel_reason.as_str())
+                .await
+            {
+                error!("Failed to send task cancelled: {}", e);
+            }
         } else if result.exit_code == 0 {
             if let Err(e) = self
                 .client
                 .send_task_completed(
                     task_id,
-                    result.exit_code,
-                    trim_output_for_storage(result.stdout),
-                    trim_output_for_storage(result.stderr),
-                    result.result,
+                    TaskCompletion {
+                        exit_code: result.exit_code,
+                        raw_exit_code,
+                        stdout: trim_output_for_storage(result.stdout),
+                        stderr: trim_output_for_storage(result.stderr),
+                        result: result.result,
+                        stdout_total_bytes: result.stdout_total_bytes,
+                        stderr_total_bytes: result.stderr_total_bytes,
+                        signal: result.signal,
+                        timing: result.timing,
+                    },
                 )
                 .await
             {
@@ -1158,12 +1872,21 @@ impl Agent {
             }
             info!("Task {} completed successfully", task_id);
         } else {
-            if result.timed_out {
+            if let Some(error) = spawn_error {
                 if let Err(e) = self
                     .client
-                    .send_task_failed(
+                    .send_task_failed_with_timing(task_id, error, result.timing)
+                    .await
+                {
+                    error!("Failed to send task failed: {}", e);
+                }
+            } else if result.timed_out {
+                if let Err(e) = self
+                    .client
+                    .send_task_failed_with_timing(
                         task_id,
-                        format!("Task timed out after {} seconds", data.timeout),
+                        format!("Task timed out after {} seconds", timeout_secs),
+                        result.timing,
                     )
                     .await
                 {
@@ -1174,10 +1897,17 @@ impl Agent {
                     .client
                     .send_task_completed(
                         task_id,
-                        result.exit_code,
-                        trim_output_for_storage(result.stdout),
-                        trim_output_for_storage(result.stderr),
-                        result.result,
+                        TaskCompletion {
+                            exit_code: result.exit_code,
+                            raw_exit_code,
+                            stdout: trim_output_for_storage(result.stdout),
+                            stderr: trim_output_for_storage(result.stderr),
+                            result: result.result,
+                            stdout_total_bytes: result.stdout_total_bytes,
+                            stderr_total_bytes: result.stderr_total_bytes,
+                            signal: result.signal,
+                            timing: result.timing,
+                        },
                     )
                     .await
                 {
@@ -1215,18 +1945,190 @@ impl Agent {
 
         // 移除运行中的任务
         self.client.clear_task_log_ack(task_id).await;
-        self.running_tasks.write().await.remove(&task_id);
+        self.finish_running_task(task_id).await;
     }
 
-    async fn handle_task_cancel(&self, task_id: i64) {
-        info!("Processing cancel for task {}", task_id);
-        let running = self.running_tasks.read().await;
-        if let Some((workspace_name, cancel_tx, _)) = running.get(&task_id) {
+    /// 计算任务的实际超时：优先服务器指定值，其次自适应超时，最后回退到配置默认值
+    async fn resolve_task_timeout(&self, requested: u64, signature: &str) -> u64 {
+        let timeout_secs = self.resolve_requested_timeout(requested, signature).await;
+        let clamped = self.config.clamp_task_timeout(timeout_secs);
+        if clamped < timeout_secs {
+            warn!(
+                "Task timeout {} seconds exceeds max_task_timeout, clamped to {} seconds",
+                timeout_secs, clamped
+            );
+        }
+        clamped
+    }
+
+    /// 服务器指定的超时优先，未指定 (0) 时按自适应超时或 task_timeout
+    async fn resolve_requested_timeout(&self, requested: u64, signature: &str) -> u64 {
+        if requested > 0 {
+            return requested;
+        }
+
+        if self.config.adaptive_timeout {
+            let adaptive = self
+                .runtime_history
+                .lock()
+                .await
+                .adaptive_timeout_secs(signature, self.config.adaptive_timeout_multiplier);
+            if let Some(timeout_secs) = adaptive {
+                info!("Using adaptive timeout of {} seconds", timeout_secs);
+                return timeout_secs;
+            }
+        }
+
+        self.config.task_timeout
+    }
+
+    /// 检查并发上限并预留资源，调用方需持有 `running_tasks` 写锁
+    async fn admit_task(
+        &self,
+        running: &HashMap<i64, RunningTask>,
+        workspace_name: &str,
+        resources: &ResourceRequest,
+    ) -> Result<(), String> {
+        if *self.shutting_down.read().await {
+            return Err("Agent is shutting down; task rejected".to_string());
+        }
+        let single_task = self.config.run_mode == RunMode::Single;
+        if single_task && self.single_task_accepted.load(Ordering::SeqCst) {
+            return Err(
+                "Agent runs a single task and has already accepted one; task rejected".to_string(),
+            );
+        }
+
+        // 结果待上报的任务进程已退出，不计入并发上限
+        let active = || running.values().filter(|task| !task.result_pending);
+        let total_limit = self.max_total_tasks;
+        let running_count = active().count();
+        if total_limit > 0 && running_count >= total_limit {
+            return Err(format!(
+                "Agent at capacity ({} running task(s), limit {})",
+                running_count, total_limit
+            ));
+        }
+
+        let limit = self.config.max_concurrent_per_workspace;
+        if limit > 0 {
+            let in_workspace = active()
+                .filter(|task| task.workspace_name == workspace_name)
+                .count();
+            if in_workspace >= limit {
+                return Err(format!(
+                    "Workspace '{}' is busy ({} running task(s), limit {})",
+                    workspace_name, in_workspace, limit
+                ));
+            }
+        }
+
+        self.resource_budget.lock().await.try_reserve(resources)?;
+        // 调用方持有 running_tasks 写锁，并发分发中只有一个能通过
+        if single_task {
+            self.single_task_accepted.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// 移除运行中的任务并归还其预留的资源，重复调用时不做任何事
+    async fn release_running_task(&self, task_id: i64) {
+        let removed = self.running_tasks.write().await.remove(&task_id);
+        self.client.set_task_running(task_id, false).await;
+        if let Some(running_task) = removed {
+            self.resource_budget
+                .lock()
+                .await
+                .release(&running_task.resources);
+        }
+    }
+
+    /// 进程已退出但终态消息尚未入队：归还并发槽位与资源预留，任务仍留在 `running_tasks` 中，
+    /// 心跳继续上报它，退出时也会等待其结果入队
+    async fn release_task_slot(&self, task_id: i64) {
+        let resources = {
+            let mut running = self.running_tasks.write().await;
+            let Some(running_task) = running.get_mut(&task_id) else {
+                return;
+            };
+            running_task.result_pending = true;
+            std::mem::take(&mut running_task.resources)
+        };
+        self.resource_budget.lock().await.release(&resources);
+    }
+
+    /// 等待重连以补发断线期间的日志；开始退出时立即返回，把剩余的宽限时间留给结果上报
+    async fn wait_for_reconnect(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.client.is_connected().await {
+                return true;
+            }
+            if Instant::now() >= deadline || *self.shutting_down.read().await {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)).await;
+        }
+    }
+
+    /// 任务处理结束：释放运行槽位，`run_mode: single` 时触发退出
+    async fn finish_running_task(&self, task_id: i64) {
+        self.release_running_task(task_id).await;
+        if self.config.run_mode == RunMode::Single {
+            self.single_task_finished.notify_one();
+        }
+    }
+
+    /// 优雅退出：拒绝新任务，取消运行中的任务并在 `shutdown_grace_secs` 内等待其上报结果，最后断开连接
+    ///
+    /// 断线期间结束、正在等待重连的任务仍在 `running_tasks` 中，同样等到其终态消息入队
+    async fn shutdown(&self) {
+        let deadline = Instant::now() + Duration::from_secs(self.config.shutdown_grace_secs);
+        {
+            // 与 admit_task 在同一把锁下置位，置位后不会再有任务登记
+            let mut running = self.running_tasks.write().await;
+            *self.shutting_down.write().await = true;
+            info!("Cancelling {} running task(s) for shutdown", running.len());
+            for running_task in running.values_mut() {
+                running_task
+                    .cancel_reason
+                    .get_or_insert(CancelReason::AgentShutdown);
+                let _ = running_task.cancel_tx.send(true);
+            }
+        }
+
+        while !self.running_tasks.read().await.is_empty() {
+            if Instant::now() >= deadline {
+                warn!(
+                    "{} task(s) still running after shutdown grace period, exiting anyway",
+                    self.running_tasks.read().await.len()
+                );
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)).await;
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !self.client.wait_for_terminal_acks(remaining).await {
+            warn!("Some task results were not acknowledged by the server before shutdown");
+        }
+        self.client.stop().await;
+    }
+
+    async fn handle_task_cancel(&self, task_id: i64, reason: CancelReason) {
+        info!(
+            "Processing cancel for task {} ({})",
+            task_id,
+            reason.as_str()
+        );
+        let mut running = self.running_tasks.write().await;
+        if let Some(running_task) = running.get_mut(&task_id) {
             info!(
                 "Sending cancel signal to task {} in workspace '{}'",
-                task_id, workspace_name
+                task_id, running_task.workspace_name
             );
-            let _ = cancel_tx.send(true);
+            running_task.cancel_reason.get_or_insert(reason);
+            let _ = running_task.cancel_tx.send(true);
             return;
         }
         warn!("Task {} not found in running tasks, cannot cancel", task_id);
@@ -1275,7 +2177,12 @@ impl Agent {
         }
 
         self.client.clear_task_log_ack(task_id).await;
-        let log_sync_state = match TaskLogSyncState::new(&self.config.workspaces_path, task_id) {
+        let log_sync_state = match TaskLogSyncState::new(
+            &self.config.workspaces_path,
+            task_id,
+            self.config.report_output_stream,
+            self.at_rest_cipher.as_ref(),
+        ) {
             Ok(state) => Arc::new(Mutex::new(state)),
             Err(e) => {
                 error!(
@@ -1352,7 +2259,13 @@ impl Agent {
 
                 if let Err(e) = self
                     .client
-                    .send_task_completed(task_id, 0, String::new(), String::new(), result)
+                    .send_task_completed(
+                        task_id,
+                        TaskCompletion {
+                            result,
+                            ..Default::default()
+                        },
+                    )
                     .await
                 {
                     error!(
@@ -1423,7 +2336,12 @@ impl Agent {
         }
 
         self.client.clear_task_log_ack(task_id).await;
-        let log_sync_state = match TaskLogSyncState::new(&self.config.workspaces_path, task_id) {
+        let log_sync_state = match TaskLogSyncState::new(
+            &self.config.workspaces_path,
+            task_id,
+            self.config.report_output_stream,
+            self.at_rest_cipher.as_ref(),
+        ) {
             Ok(state) => Arc::new(Mutex::new(state)),
             Err(e) => {
                 error!(
@@ -1460,13 +2378,7 @@ impl Agent {
 
         if let Err(e) = self
             .client
-            .send_task_completed(
-                task_id,
-                0,
-                String::new(),
-                String::new(),
-                HashMap::new(),
-            )
+            .send_task_completed(task_id, TaskCompletion::default())
             .await
         {
             error!(
@@ -1486,7 +2398,12 @@ fn main() {
     let cli = Cli::parse();
 
     match cli {
-        Cli::Run { config, service_name } => {
+        Cli::Run {
+            config,
+            service_name,
+            dry_run,
+            once,
+        } => {
             // Windows: 尝试以 SCM 服务模式运行
             // 如果进程由 SCM 启动，service_dispatcher::start 会阻塞直到服务停止
             // 如果由用户直接启动，会失败并回退到前台模式
@@ -1500,25 +2417,85 @@ fn main() {
             // 前台模式运行（用户直接启动或 Linux/macOS 服务管理器启动）
             let _ = service_name; // Linux/macOS 不需要此参数
             let rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
-            rt.block_on(run_agent(config));
+            rt.block_on(run_agent(config, dry_run, once));
         }
         Cli::Service { action } => {
             service::handle_service_command(action);
         }
+        Cli::Validate { config } => {
+            validate_config_file(config);
+        }
+        Cli::Doctor { config, name, json } => {
+            let rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
+            let report = rt.block_on(DoctorReport::collect(config, &name));
+            if json {
+                match serde_json::to_string_pretty(&report) {
+                    Ok(output) => println!("{}", output),
+                    Err(e) => {
+                        eprintln!("诊断信息序列化失败: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                print!("{}", report.render_text());
+            }
+        }
+    }
+}
+
+/// `validate` 子命令：加载并校验配置，错误输出到 stderr 并以非零码退出
+fn validate_config_file(config_path: PathBuf) {
+    let config = match load_config(config_path.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("配置加载失败: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    for warning in config.warnings() {
+        eprintln!("配置警告: {}", warning);
+    }
+
+    if let Err(errors) = config.validate() {
+        for error in errors {
+            eprintln!("配置错误: {}", error);
+        }
+        std::process::exit(1);
     }
+
+    println!("配置有效: {}", config_path.display());
 }
 
-pub async fn run_agent(config_path: PathBuf) {
-    let config = match load_config(config_path) {
+pub async fn run_agent(config_path: PathBuf, dry_run: bool, once: bool) {
+    let mut config = match load_config(config_path) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("配置加载失败: {}", e);
             std::process::exit(1);
         }
     };
+    if dry_run {
+        config.dry_run = true;
+    }
+    if once {
+        config.run_mode = RunMode::Single;
+    }
 
-    // 配置日志（_log_guard 必须保持存活，否则文件日志停止写入）
-    let _log_guard = setup_logging(&config.log_level, config.log_file.as_ref());
+    // 配置日志（log_guard 必须保持存活，否则文件日志停止写入）
+    let (log_guard, log_level_setter) = setup_logging(
+        &config.log_level,
+        config.log_format,
+        config.log_file.as_ref(),
+        config.log_max_files,
+        config.log_max_size_bytes(),
+    );
+    if config.dry_run {
+        warn!("Dry-run mode enabled: tasks will report planned commands without executing them");
+    }
+    if config.run_mode == RunMode::Single {
+        info!("Single-task mode: the agent exits after its first task finishes");
+    }
 
     // 验证配置
     if let Err(errors) = config.validate() {
@@ -1531,7 +2508,24 @@ pub async fn run_agent(config_path: PathBuf) {
     // 清理上次更新遗留的 .bak 文件
     self_update::cleanup_previous_update();
 
-    let mut persisted_state = match PersistedStateStore::load(&config.workspaces_path) {
+    let at_rest_cipher = match config
+        .at_rest_encryption_key
+        .as_deref()
+        .map(AtRestCipher::from_config_value)
+        .transpose()
+    {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            error!("Failed to load at-rest encryption key: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let state_file = config
+        .state_file
+        .clone()
+        .unwrap_or_else(|| PersistedStateStore::default_state_file(&config.workspaces_path));
+    let loaded_state = PersistedStateStore::load_from_path(state_file, at_rest_cipher.clone());
+    let mut persisted_state = match loaded_state {
         Ok(store) => store,
         Err(e) => {
             error!("Failed to load persisted agent state: {}", e);
@@ -1546,11 +2540,58 @@ pub async fn run_agent(config_path: PathBuf) {
     }
 
     // 创建并运行 Agent
-    let agent = Agent::new(config, persisted_state);
+    let agent = Agent::new(config, persisted_state, at_rest_cipher, log_level_setter);
+
+    match agent.start().await {
+        Ok(Some(exit_code)) => {
+            info!("Single task finished, exiting with code {}", exit_code);
+            // process::exit 不运行析构函数，先写出缓冲中的文件日志
+            drop(log_guard);
+            std::process::exit(exit_code);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Agent 运行失败: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    if let Err(e) = agent.start().await {
-        error!("Agent 运行失败: {}", e);
-        std::process::exit(1);
+/// 等待 Ctrl-C，Unix 上还包括 SIGTERM，Windows 上还包括 SCM Stop；无法安装信号处理时永不返回
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = ctrl_c_or_pending() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                ctrl_c_or_pending().await;
+            }
+        }
+    }
+    #[cfg(windows)]
+    tokio::select! {
+        _ = ctrl_c_or_pending() => {}
+        _ = SERVICE_STOP.notified() => {}
+    }
+    #[cfg(not(any(unix, windows)))]
+    ctrl_c_or_pending().await;
+}
+
+/// SCM 发来的 Stop 请求，与 Ctrl-C 一样走 `Agent::shutdown` 的优雅退出流程
+#[cfg(windows)]
+static SERVICE_STOP: Notify = Notify::const_new();
+
+async fn ctrl_c_or_pending() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
     }
 }
 
@@ -1577,7 +2618,6 @@ windows_service::define_windows_service!(ffi_service_main, windows_service_main)
 /// Windows 服务主入口（由 SCM 在后台线程调用）
 #[cfg(windows)]
 fn windows_service_main(_arguments: Vec<std::ffi::OsString>) {
-    use std::sync::mpsc;
     use std::time::Duration;
     use windows_service::{
         service::{
@@ -1587,9 +2627,6 @@ fn windows_service_main(_arguments: Vec<std::ffi::OsString>) {
         service_control_handler::{self, ServiceControlHandlerResult},
     };
 
-    // 创建 channel：控制处理器通过它发送 Stop 信号
-    let (shutdown_tx, shutdown_rx) = mpsc::channel::<()>();
-
     // 从进程命令行参数中获取服务名称（多实例支持）
     let service_name = extract_service_name_from_process_args()
         .unwrap_or_else(|| service::DEFAULT_SERVICE_NAME.to_string());
@@ -1599,7 +2636,8 @@ fn windows_service_main(_arguments: Vec<std::ffi::OsString>) {
         match service_control_handler::register(&service_name, move |control_event| {
             match control_event {
                 ServiceControl::Stop => {
-                    let _ = shutdown_tx.send(());
+                    // 交给 Agent::start 中的信号任务，取消任务并等待结果确认后 run_agent 才返回
+                    SERVICE_STOP.notify_one();
                     ServiceControlHandlerResult::NoError
                 }
                 ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
@@ -1658,28 +2696,9 @@ fn windows_service_main(_arguments: Vec<std::ffi::OsString>) {
         }
     };
 
-    rt.block_on(async {
-        // 启动 Agent
-        let agent_handle = tokio::spawn(run_agent(config_path));
-        tokio::pin!(agent_handle);
-
-        // 在后台等待 SCM Stop 信号
-        let stop_waiter = tokio::task::spawn_blocking(move || {
-            let _ = shutdown_rx.recv();
-        });
-        tokio::pin!(stop_waiter);
-
-        // 等待 Stop 信号或 Agent 自行退出（以先发生者为准）
-        tokio::select! {
-            _ = &mut stop_waiter => {
-                // 收到 Stop 信号，中止 Agent
-                agent_handle.abort();
-            }
-            _ = &mut agent_handle => {
-                // Agent 自行退出（例如自更新场景的 exit(42)）
-            }
-        }
-    });
+    // Stop 请求经 shutdown_signal 触发优雅退出，run_agent 在退出完成后返回；
+    // 自更新等场景下 Agent 也可能自行退出
+    rt.block_on(run_agent(config_path, false, false));
 
     // 上报 Stopped 状态
     let _ = status_handle.set_service_status(ServiceStatus {
@@ -1696,8 +2715,7 @@ fn windows_service_main(_arguments: Vec<std::ffi::OsString>) {
 /// 从当前进程命令行参数中提取 --config 值
 #[cfg(windows)]
 fn extract_config_from_process_args() -> Option<PathBuf> {
-    extract_arg_value("--config", Some("-c"))
-        .map(PathBuf::from)
+    extract_arg_value("--config", Some("-c")).map(PathBuf::from)
 }
 
 /// 从当前进程命令行参数中提取 --service-name 值
@@ -1724,3 +2742,619 @@ fn extract_arg_value(long_name: &str, short_name: Option<&str>) -> Option<String
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tasknexus_agent::config::TaskLimit;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+    fn unique_temp_dir(name: &str) -> PathBuf {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        std::env::temp_dir().join(format!("tasknexus_{}_{}", name, unique))
+    }
+
+    fn test_agent(port: u16, name: &str) -> Arc<Agent> {
+        test_agent_with(port, name, |_| {})
+    }
+
+    fn test_agent_with(
+        port: u16,
+        name: &str,
+        configure: impl FnOnce(&mut AgentConfig),
+    ) -> Arc<Agent> {
+        let mut config = AgentConfig {
+            server: format!("ws://127.0.0.1:{}/ws/agent/", port),
+            workspaces_path: unique_temp_dir(name),
+            heartbeat_interval: 3600,
+            ping_interval_secs: 0,
+            reconnect_interval: 1,
+            shell_login_interactive: false,
+            ..AgentConfig::default()
+        };
+        configure(&mut config);
+        fs::create_dir_all(&config.workspaces_path).unwrap();
+        let persisted_state = PersistedStateStore::load(&config.workspaces_path).unwrap();
+        let log_level_setter: LogLevelSetter = Arc::new(|_: &str| Ok(()));
+        Arc::new(Agent::new(config, persisted_state, None, log_level_setter))
+    }
+
+    /// 运行客户端主循环，只把任务分发与取消交给 Agent 处理
+    fn spawn_client(agent: Arc<Agent>) -> tokio::task::JoinHandle<tasknexus_agent::Result<()>> {
+        tokio::spawn(async move {
+            let dispatch_agent = agent.clone();
+            let cancel_agent = agent.clone();
+            agent
+                .client
+                .run(
+                    move |data| {
+                        let agent = dispatch_agent.clone();
+                        async move {
+                            agent.handle_task_dispatch(data).await;
+                        }
+                    },
+                    move |task_id| {
+                        let agent = cancel_agent.clone();
+                        async move {
+                            agent
+                                .handle_task_cancel(task_id, CancelReason::ServerRequested)
+                                .await;
+                        }
+                    },
+                    |_| async {},
+                    |_| async {},
+                    |_| async {},
+                    |_| async {},
+                    || {},
+                    || {},
+                )
+                .await
+        })
+    }
+
+    /// 服务器一次性下发 `dispatches`，收集 Agent 发来的消息直到 2 秒内没有新消息
+    fn serve_dispatches(
+        listener: TcpListener,
+        dispatches: Vec<serde_json::Value>,
+    ) -> tokio::task::JoinHandle<Vec<serde_json::Value>> {
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for dispatch in dispatches {
+                ws.send(Message::Text(dispatch.to_string())).await.unwrap();
+            }
+            let mut messages = Vec::new();
+            while let Ok(message) =
+                tokio::time::timeout(Duration::from_secs(2), next_json(&mut ws)).await
+            {
+                messages.push(message);
+            }
+            messages
+        })
+    }
+
+    fn task_dispatch(task_id: i64, workspace_name: &str, command: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": "task_dispatch",
+            "task_id": task_id,
+            "workspace_name": workspace_name,
+            "command": command,
+        })
+    }
+
+    /// 收到过 `kind` 类型消息的任务 ID（终态消息可能重发，按任务去重）
+    fn task_ids_of(messages: &[serde_json::Value], kind: &str) -> Vec<i64> {
+        let mut ids: Vec<i64> = messages
+            .iter()
+            .filter(|message| message["type"] == kind)
+            .filter_map(|message| message["task_id"].as_i64())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// 等待任务进程退出并进入结果待上报状态
+    async fn wait_for_result_pending(agent: &Agent, task_id: i64) -> bool {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let pending = agent
+                    .running_tasks
+                    .read()
+                    .await
+                    .get(&task_id)
+                    .is_some_and(|task| task.result_pending);
+                if pending {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    async fn next_json(ws: &mut WebSocketStream<TcpStream>) -> serde_json::Value {
+        loop {
+            let message = ws.next().await.expect("connection closed").unwrap();
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// 按服务器的方式拼接日志：追加必须从已确认的偏移量之内开始，返回新的确认偏移量
+    async fn accept_append(
+        ws: &mut WebSocketStream<TcpStream>,
+        log: &mut String,
+        message: &serde_json::Value,
+    ) -> u64 {
+        let start_offset = message["start_offset"].as_u64().unwrap() as usize;
+        assert!(
+            start_offset <= log.len(),
+            "append at {} leaves a gap after {}",
+            start_offset,
+            log.len()
+        );
+        log.truncate(start_offset);
+        log.push_str(message["content"].as_str().unwrap());
+        let ack = serde_json::json!({
+            "type": "task_log_ack",
+            "task_id": message["task_id"],
+            "next_offset": log.len(),
+        });
+        ws.send(Message::Text(ack.to_string())).await.unwrap();
+        log.len() as u64
+    }
+
+    #[test]
+    fn log_appends_do_not_mix_output_streams_when_tagged() {
+        let workspaces_path = unique_temp_dir("stream_runs");
+        let mut state = TaskLogSyncState::new(&workspaces_path, 1, true, None).unwrap();
+        state.ingest_chunk("out1\nout2\n", false).unwrap();
+        let stdout_end = state.committed_offset;
+        state.ingest_chunk("err\n", true).unwrap();
+        let stderr_end = state.committed_offset;
+        state.ingest_chunk("out3\n", false).unwrap();
+
+        assert_eq!(state.stream_run_at(0), (stdout_end as usize, Some(false)));
+        assert_eq!(
+            state.stream_run_at(stdout_end),
+            ((stderr_end - stdout_end) as usize, Some(true))
+        );
+        assert_eq!(
+            state.stream_run_at(stderr_end),
+            ((state.committed_offset - stderr_end) as usize, Some(false))
+        );
+        // 重发未确认的区间时输出流不变；确认后才删除
+        assert_eq!(state.stream_run_at(0), (stdout_end as usize, Some(false)));
+        state.acked_offset = stdout_end;
+        state.prune_stream_runs();
+        assert_eq!(state.stream_runs.len(), 2);
+        assert_eq!(
+            state.stream_run_at(stdout_end),
+            ((stderr_end - stdout_end) as usize, Some(true))
+        );
+
+        let mut untagged = TaskLogSyncState::new(&workspaces_path, 2, false, None).unwrap();
+        untagged.ingest_chunk("out\n", false).unwrap();
+        untagged.ingest_chunk("err\n", true).unwrap();
+        assert_eq!(untagged.stream_run_at(0), (MAX_LOG_CHUNK_BYTES, None));
+        let _ = fs::remove_dir_all(&workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn unspecified_dispatch_timeout_falls_back_to_task_timeout() {
+        let agent = test_agent(0, "dispatch_timeout");
+        let signature = task_signature("default", "command", "build.sh");
+
+        assert_eq!(agent.resolve_task_timeout(120, &signature).await, 120);
+        assert_eq!(
+            agent.resolve_task_timeout(0, &signature).await,
+            agent.config.task_timeout
+        );
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn duplicate_dispatch_of_running_task_is_ignored() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent(listener.local_addr().unwrap().port(), "duplicate_dispatch");
+        let marker = agent.config.workspaces_path.join("runs");
+        let command = format!("sleep 0.5; echo run >> '{}'", marker.display());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let dispatch = serde_json::json!({
+                "type": "task_dispatch",
+                "task_id": 11,
+                "workspace_name": "duplicate",
+                "command": command,
+            });
+            // 服务器重试：同一任务在运行期间被再次下发
+            for _ in 0..2 {
+                ws.send(Message::Text(dispatch.to_string())).await.unwrap();
+            }
+            let mut types = Vec::new();
+            while let Ok(message) =
+                tokio::time::timeout(Duration::from_secs(2), next_json(&mut ws)).await
+            {
+                types.push(message["type"].as_str().unwrap_or_default().to_string());
+            }
+            types
+        });
+        let client = spawn_client(agent.clone());
+
+        let types = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .unwrap()
+            .unwrap();
+        let count = |kind: &str| types.iter().filter(|t| t.as_str() == kind).count();
+        assert_eq!(count("task_failed"), 0, "{:?}", types);
+        assert_eq!(count("task_started"), 1, "{:?}", types);
+        assert_eq!(count("task_completed"), 1, "{:?}", types);
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "run\n");
+        assert!(agent
+            .metrics
+            .render()
+            .contains("tasknexus_agent_tasks_dispatched_total 1\n"));
+
+        agent.client.stop().await;
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_busy_workspace_is_rejected_at_the_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent_with(
+            listener.local_addr().unwrap().port(),
+            "workspace_limit",
+            |config| config.max_concurrent_per_workspace = 1,
+        );
+        let server = serve_dispatches(
+            listener,
+            vec![
+                task_dispatch(21, "shared", "sleep 1"),
+                task_dispatch(22, "shared", "sleep 1"),
+                task_dispatch(23, "other", "sleep 1"),
+            ],
+        );
+        let client = spawn_client(agent.clone());
+
+        let messages = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .unwrap()
+            .unwrap();
+        let failed: Vec<_> = messages
+            .iter()
+            .filter(|message| message["type"] == "task_failed")
+            .collect();
+        assert_eq!(
+            task_ids_of(&messages, "task_failed").len(),
+            1,
+            "{:?}",
+            messages
+        );
+        let busy_task = failed[0]["task_id"].as_i64().unwrap();
+        assert!([21, 22].contains(&busy_task));
+        assert!(
+            failed[0]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Workspace 'shared' is busy"),
+            "{:?}",
+            failed[0]
+        );
+        let completed = task_ids_of(&messages, "task_completed");
+        assert_eq!(completed.len(), 2, "{:?}", messages);
+        assert!(completed.contains(&23) && !completed.contains(&busy_task));
+
+        agent.client.stop().await;
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn dispatch_beyond_max_total_tasks_is_rejected_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent_with(
+            listener.local_addr().unwrap().port(),
+            "total_limit",
+            |config| config.max_total_tasks = TaskLimit::Fixed(2),
+        );
+        // 三个任务同时下发到不同的工作空间，只有总数上限起作用
+        let server = serve_dispatches(
+            listener,
+            (41..=43)
+                .map(|task_id| task_dispatch(task_id, &format!("ws{}", task_id), "sleep 1"))
+                .collect(),
+        );
+        let client = spawn_client(agent.clone());
+
+        let messages = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .unwrap()
+            .unwrap();
+        let failed: Vec<_> = messages
+            .iter()
+            .filter(|message| message["type"] == "task_failed")
+            .collect();
+        assert_eq!(
+            task_ids_of(&messages, "task_failed").len(),
+            1,
+            "{:?}",
+            messages
+        );
+        assert!(
+            failed[0]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Agent at capacity"),
+            "{:?}",
+            failed[0]
+        );
+        assert_eq!(
+            task_ids_of(&messages, "task_completed").len(),
+            2,
+            "{:?}",
+            messages
+        );
+
+        agent.client.stop().await;
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_only_the_named_task_in_a_shared_workspace() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent(listener.local_addr().unwrap().port(), "workspace_cancel");
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for task_id in [31, 32, 33] {
+                let dispatch = task_dispatch(task_id, "shared", "sleep 2");
+                ws.send(Message::Text(dispatch.to_string())).await.unwrap();
+            }
+            let mut messages = Vec::new();
+            while task_ids_of(&messages, "task_started").len() < 3 {
+                messages.push(next_json(&mut ws).await);
+            }
+            let cancel = serde_json::json!({"type": "task_cancel", "task_id": 32});
+            ws.send(Message::Text(cancel.to_string())).await.unwrap();
+            while let Ok(message) =
+                tokio::time::timeout(Duration::from_secs(3), next_json(&mut ws)).await
+            {
+                messages.push(message);
+            }
+            messages
+        });
+        let client = spawn_client(agent.clone());
+
+        let messages = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            task_ids_of(&messages, "task_cancelled"),
+            vec![32],
+            "{:?}",
+            messages
+        );
+        assert_eq!(
+            task_ids_of(&messages, "task_completed"),
+            vec![31, 33],
+            "{:?}",
+            messages
+        );
+        assert!(task_ids_of(&messages, "task_failed").is_empty());
+
+        agent.client.stop().await;
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn log_backpressure_blocks_until_acked_and_expires_after_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent(listener.local_addr().unwrap().port(), "backpressure");
+        // 服务器只接收消息，从不确认日志
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+        let client = spawn_client(agent.clone());
+        let connected = agent.client.wait_until_connected(Duration::from_secs(5));
+        assert!(connected.await);
+
+        let backlog = format!("{}\n", "x".repeat(1023)).repeat(2048);
+        let new_state = |task_id| {
+            let mut state =
+                TaskLogSyncState::new(&agent.config.workspaces_path, task_id, false, None).unwrap();
+            state.ingest_chunk(&backlog, false).unwrap();
+            assert!(state.unacked_bytes() > PROGRESS_BACKPRESSURE_MAX_UNACKED_BYTES);
+            Arc::new(Mutex::new(state))
+        };
+
+        // 确认偏移量追上后放行
+        let state = new_state(1);
+        let waiter = {
+            let state = state.clone();
+            let client = agent.client.clone();
+            tokio::spawn(async move {
+                wait_for_log_backlog(&state, &client, Duration::from_secs(30)).await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiter.is_finished(), "output callback did not block");
+        {
+            let mut guard = state.lock().await;
+            guard.acked_offset = guard.committed_offset;
+        }
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("output callback stayed blocked after ack")
+            .unwrap();
+
+        // 确认偏移量不前进：等到超时，之后同一任务的输出不再等待
+        let state = new_state(2);
+        let started = Instant::now();
+        wait_for_log_backlog(&state, &agent.client, Duration::from_millis(300)).await;
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        let started = Instant::now();
+        wait_for_log_backlog(&state, &agent.client, Duration::from_secs(30)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        agent.client.stop().await;
+        let _ = client.await;
+        server.abort();
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn output_buffered_while_disconnected_is_flushed_after_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent(listener.local_addr().unwrap().port(), "offline_flush");
+        let (disconnected_tx, disconnected_rx) = oneshot::channel();
+        let (reconnect_tx, reconnect_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let mut log = String::new();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let dispatch = serde_json::json!({
+                "type": "task_dispatch",
+                "task_id": 7,
+                "workspace_name": "offline",
+                "command": "echo before; sleep 2; echo after",
+            });
+            ws.send(Message::Text(dispatch.to_string())).await.unwrap();
+            // 收到第一段输出后断开连接，任务仍在运行
+            loop {
+                let message = next_json(&mut ws).await;
+                if message["type"] == "task_log_append" {
+                    accept_append(&mut ws, &mut log, &message).await;
+                    break;
+                }
+            }
+            drop(ws);
+            disconnected_tx.send(()).unwrap();
+
+            reconnect_rx.await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            loop {
+                let message = next_json(&mut ws).await;
+                match message["type"].as_str() {
+                    Some("task_log_append") => {
+                        accept_append(&mut ws, &mut log, &message).await;
+                    }
+                    Some("task_completed") => return (log, message),
+                    _ => {}
+                }
+            }
+        });
+        let client = spawn_client(agent.clone());
+
+        disconnected_rx.await.unwrap();
+        // 任务在断线期间结束，等待重连时不再占用运行槽位，但心跳仍上报它
+        assert!(
+            wait_for_result_pending(&agent, 7).await,
+            "running slot was held while waiting for reconnect"
+        );
+        assert!(!agent.client.is_connected().await);
+        assert_eq!(agent.client.running_task_count().await, 1);
+
+        reconnect_tx.send(()).unwrap();
+        let (log, completed) = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .expect("buffered output was not flushed after reconnect")
+            .unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{:?}", log);
+        assert!(lines[0].ends_with("] before"), "{:?}", log);
+        assert!(lines[1].ends_with("] after"), "{:?}", log);
+        assert_eq!(completed["task_id"], 7);
+        assert_eq!(completed["exit_code"], 0);
+
+        agent.client.stop().await;
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_result_of_task_finished_while_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = test_agent_with(
+            listener.local_addr().unwrap().port(),
+            "offline_shutdown",
+            |config| config.shutdown_grace_secs = 20,
+        );
+        let (disconnected_tx, disconnected_rx) = oneshot::channel();
+        let (reconnect_tx, reconnect_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let dispatch = task_dispatch(8, "offline", "echo before; sleep 1");
+            ws.send(Message::Text(dispatch.to_string())).await.unwrap();
+            loop {
+                if next_json(&mut ws).await["type"] == "task_log_append" {
+                    break;
+                }
+            }
+            drop(ws);
+            disconnected_tx.send(()).unwrap();
+
+            reconnect_rx.await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            loop {
+                let message = next_json(&mut ws).await;
+                if message["type"] == "task_completed" {
+                    let ack = serde_json::json!({
+                        "type": "task_state_ack",
+                        "task_id": message["task_id"],
+                        "status": "COMPLETED",
+                        "accepted": true,
+                    });
+                    ws.send(Message::Text(ack.to_string())).await.unwrap();
+                    return message;
+                }
+            }
+        });
+        let client = spawn_client(agent.clone());
+
+        disconnected_rx.await.unwrap();
+        assert!(wait_for_result_pending(&agent, 8).await);
+
+        // 退出时结果尚未入队；重连后应上报结果，而不是在结果入队前断开连接
+        let shutdown_agent = agent.clone();
+        let shutdown = tokio::spawn(async move { shutdown_agent.shutdown().await });
+        reconnect_tx.send(()).unwrap();
+        let completed = tokio::time::timeout(Duration::from_secs(20), server)
+            .await
+            .expect("result was not reported before shutdown finished")
+            .unwrap();
+        assert_eq!(completed["task_id"], 8);
+        assert_eq!(completed["exit_code"], 0);
+
+        tokio::time::timeout(Duration::from_secs(20), shutdown)
+            .await
+            .expect("shutdown did not finish")
+            .unwrap();
+        assert!(agent.running_tasks.read().await.is_empty());
+        let _ = client.await;
+        let _ = fs::remove_dir_all(&agent.config.workspaces_path);
+    }
+}
@@ -0,0 +1,259 @@
+//! Prometheus 指标
+//!
+//! 计数器始终编译并由任务处理与客户端更新；`/metrics` HTTP 端点需要启用 `metrics` feature，
+//! 在配置了 `metrics_addr` 时由 `main` 启动。
+
+use crate::executor::ExecutionResult;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// 任务耗时直方图的桶上限(秒)
+const DURATION_BUCKETS_SECS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0,
+];
+
+/// Agent 运行指标，各字段为原子计数，可在任务与连接之间共享
+#[derive(Debug, Default)]
+pub struct AgentMetrics {
+    tasks_dispatched: AtomicU64,
+    tasks_completed: AtomicU64,
+    tasks_failed: AtomicU64,
+    tasks_cancelled: AtomicU64,
+    tasks_timed_out: AtomicU64,
+    reconnects: AtomicU64,
+    running_tasks: AtomicI64,
+    duration_buckets: [AtomicU64; DURATION_BUCKETS_SECS.len()],
+    duration_count: AtomicU64,
+    duration_sum_ms: AtomicU64,
+}
+
+impl AgentMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 收到任务分发
+    pub fn record_task_dispatched(&self) {
+        self.tasks_dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 任务在执行前被拒绝（更新中、命令策略、磁盘空间、并发上限等），计为失败
+    pub fn record_task_rejected(&self) {
+        self.tasks_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 任务执行结束：按取消、超时、成功、失败分类计数，并记录耗时
+    pub fn record_task_finished(&self, result: &ExecutionResult) {
+        let counter = if result.cancelled {
+            &self.tasks_cancelled
+        } else if result.timed_out {
+            &self.tasks_timed_out
+        } else if result.exit_code == 0 && !result.spawn_failed {
+            &self.tasks_completed
+        } else {
+            &self.tasks_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(timing) = &result.timing {
+            let secs = timing.duration_ms as f64 / 1000.0;
+            for (bucket, upper) in self.duration_buckets.iter().zip(DURATION_BUCKETS_SECS) {
+                if secs <= upper {
+                    bucket.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            self.duration_count.fetch_add(1, Ordering::Relaxed);
+            self.duration_sum_ms
+                .fetch_add(timing.duration_ms, Ordering::Relaxed);
+        }
+    }
+
+    /// 与服务器的连接断开后准备重连
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 当前运行中的任务数
+    pub fn set_running_tasks(&self, count: usize) {
+        self.running_tasks.store(count as i64, Ordering::Relaxed);
+    }
+
+    /// 以 Prometheus 文本格式输出全部指标
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP tasknexus_agent_tasks_dispatched_total Task dispatches received.\n");
+        out.push_str("# TYPE tasknexus_agent_tasks_dispatched_total counter\n");
+        let _ = writeln!(
+            out,
+            "tasknexus_agent_tasks_dispatched_total {}",
+            self.tasks_dispatched.load(Ordering::Relaxed)
+        );
+
+        let outcomes = [
+            ("completed", &self.tasks_completed),
+            ("failed", &self.tasks_failed),
+            ("cancelled", &self.tasks_cancelled),
+            ("timed_out", &self.tasks_timed_out),
+        ];
+        out.push_str("# HELP tasknexus_agent_tasks_finished_total Finished tasks by outcome.\n");
+        out.push_str("# TYPE tasknexus_agent_tasks_finished_total counter\n");
+        for (outcome, counter) in outcomes {
+            let _ = writeln!(
+                out,
+                "tasknexus_agent_tasks_finished_total{{outcome=\"{}\"}} {}",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP tasknexus_agent_reconnects_total Reconnect attempts to the server.\n");
+        out.push_str("# TYPE tasknexus_agent_reconnects_total counter\n");
+        let _ = writeln!(
+            out,
+            "tasknexus_agent_reconnects_total {}",
+            self.reconnects.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP tasknexus_agent_running_tasks Tasks currently running.\n");
+        out.push_str("# TYPE tasknexus_agent_running_tasks gauge\n");
+        let _ = writeln!(
+            out,
+            "tasknexus_agent_running_tasks {}",
+            self.running_tasks.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP tasknexus_agent_task_duration_seconds Task execution time.\n");
+        out.push_str("# TYPE tasknexus_agent_task_duration_seconds histogram\n");
+        for (bucket, upper) in self.duration_buckets.iter().zip(DURATION_BUCKETS_SECS) {
+            let _ = writeln!(
+                out,
+                "tasknexus_agent_task_duration_seconds_bucket{{le=\"{}\"}} {}",
+                upper,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.duration_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "tasknexus_agent_task_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "tasknexus_agent_task_duration_seconds_sum {}",
+            self.duration_sum_ms.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        let _ = writeln!(out, "tasknexus_agent_task_duration_seconds_count {}", count);
+        out
+    }
+}
+
+/// 在 `listener` 上提供 `GET /metrics`，其余路径返回 404；只在进程退出时返回
+#[cfg(feature = "metrics")]
+pub async fn serve(listener: tokio::net::TcpListener, metrics: std::sync::Arc<AgentMetrics>) {
+    use crate::http_server::HttpResponse;
+
+    crate::http_server::serve(listener, move |method, path| {
+        let response = if method == hyper::Method::GET && path == "/metrics" {
+            HttpResponse {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: metrics.render(),
+            }
+        } else {
+            HttpResponse::not_found()
+        };
+        std::future::ready(response)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AgentMetrics;
+    use crate::executor::{ExecutionResult, TaskTiming};
+
+    fn finished(
+        exit_code: i32,
+        timed_out: bool,
+        cancelled: bool,
+        duration_ms: u64,
+    ) -> ExecutionResult {
+        ExecutionResult::new(exit_code)
+            .with_timed_out(timed_out)
+            .with_cancelled(cancelled)
+            .with_timing(Some(TaskTiming {
+                duration_ms,
+                started_at: String::new(),
+                finished_at: String::new(),
+            }))
+    }
+
+    #[test]
+    fn render_reports_outcomes_gauge_and_duration_histogram() {
+        let metrics = AgentMetrics::new();
+        for _ in 0..4 {
+            metrics.record_task_dispatched();
+        }
+        metrics.record_task_finished(&finished(0, false, false, 500));
+        metrics.record_task_finished(&finished(2, false, false, 7_000));
+        metrics.record_task_finished(&finished(-1, true, false, 40_000));
+        metrics.record_task_finished(&finished(-1, false, true, 100));
+        metrics.record_task_rejected();
+        metrics.record_reconnect();
+        metrics.set_running_tasks(3);
+
+        let text = metrics.render();
+        for line in [
+            "tasknexus_agent_tasks_dispatched_total 4",
+            "tasknexus_agent_tasks_finished_total{outcome=\"completed\"} 1",
+            "tasknexus_agent_tasks_finished_total{outcome=\"failed\"} 2",
+            "tasknexus_agent_tasks_finished_total{outcome=\"cancelled\"} 1",
+            "tasknexus_agent_tasks_finished_total{outcome=\"timed_out\"} 1",
+            "tasknexus_agent_reconnects_total 1",
+            "tasknexus_agent_running_tasks 3",
+            "tasknexus_agent_task_duration_seconds_bucket{le=\"1\"} 2",
+            "tasknexus_agent_task_duration_seconds_bucket{le=\"10\"} 3",
+            "tasknexus_agent_task_duration_seconds_bucket{le=\"60\"} 4",
+            "tasknexus_agent_task_duration_seconds_bucket{le=\"+Inf\"} 4",
+            "tasknexus_agent_task_duration_seconds_sum 47.6",
+            "tasknexus_agent_task_duration_seconds_count 4",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn serve_exposes_metrics_endpoint() {
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let metrics = Arc::new(AgentMetrics::new());
+        metrics.set_running_tasks(2);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(super::serve(listener, metrics));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("\ntasknexus_agent_running_tasks 2\n"),
+            "{}",
+            response
+        );
+        server.abort();
+    }
+}
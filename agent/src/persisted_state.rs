@@ -2,10 +2,12 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::at_rest::{self, AtRestCipher};
 use crate::error::{AgentError, Result};
 
-const STATE_DIR_NAME: &str = ".tasknexus_agent";
+pub(crate) const STATE_DIR_NAME: &str = ".tasknexus_agent";
 const STATE_FILE_NAME: &str = "agent_state.json";
 const CURRENT_STATE_VERSION: u32 = 1;
 
@@ -114,6 +116,9 @@ pub struct PersistedTaskState {
     pub final_payload: PersistedFinalPayload,
     #[serde(default)]
     pub local_log_path: String,
+    /// 任务开始运行的时间（Unix 秒），旧版本状态文件中为 0
+    #[serde(default)]
+    pub started_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -136,27 +141,43 @@ impl Default for PersistedStateFile {
 pub struct PersistedStateStore {
     state_file_path: PathBuf,
     tasks: HashMap<i64, PersistedTaskState>,
+    cipher: Option<AtRestCipher>,
 }
 
 impl PersistedStateStore {
     pub fn load(workspaces_path: &Path) -> Result<Self> {
-        let state_dir = workspaces_path.join(STATE_DIR_NAME);
-        let state_file_path = state_dir.join(STATE_FILE_NAME);
+        Self::load_with_cipher(workspaces_path, None)
+    }
+
+    /// 加载持久化状态，配置了加密器时以密文形式落盘
+    pub fn load_with_cipher(workspaces_path: &Path, cipher: Option<AtRestCipher>) -> Result<Self> {
+        Self::load_from_path(Self::default_state_file(workspaces_path), cipher)
+    }
+
+    /// 默认状态文件路径：`<workspaces_path>/.tasknexus_agent/agent_state.json`
+    pub fn default_state_file(workspaces_path: &Path) -> PathBuf {
+        workspaces_path.join(STATE_DIR_NAME).join(STATE_FILE_NAME)
+    }
+
+    /// 从指定路径加载持久化状态，文件不存在时返回空状态
+    pub fn load_from_path(state_file_path: PathBuf, cipher: Option<AtRestCipher>) -> Result<Self> {
         if !state_file_path.exists() {
             return Ok(Self {
                 state_file_path,
                 tasks: HashMap::new(),
+                cipher,
             });
         }
 
-        let content = fs::read_to_string(&state_file_path).map_err(|e| {
+        let content = fs::read(&state_file_path).map_err(|e| {
             AgentError::Execution(format!(
                 "Failed to read persisted agent state '{}': {}",
                 state_file_path.display(),
                 e
             ))
         })?;
-        let decoded: PersistedStateFile = serde_json::from_str(&content).map_err(|e| {
+        let content = at_rest::open(cipher.as_ref(), content)?;
+        let decoded: PersistedStateFile = serde_json::from_slice(&content).map_err(|e| {
             AgentError::Execution(format!(
                 "Failed to parse persisted agent state '{}': {}",
                 state_file_path.display(),
@@ -173,6 +194,7 @@ impl PersistedStateStore {
         Ok(Self {
             state_file_path,
             tasks,
+            cipher,
         })
     }
 
@@ -187,6 +209,20 @@ impl PersistedStateStore {
         changed
     }
 
+    /// 将重启前未结束的任务标记为失败待同步，返回这些任务 ID
+    pub fn fail_lost_tasks(&mut self, error: &str) -> Vec<i64> {
+        let mut failed = Vec::new();
+        for task in self.tasks.values_mut() {
+            if task.local_state == PersistedTaskStateKind::LostOnRestart {
+                task.local_state = PersistedTaskStateKind::FailedPendingSync;
+                task.final_payload = PersistedFinalPayload::failed(error.to_string());
+                failed.push(task.task_id);
+            }
+        }
+        failed.sort();
+        failed
+    }
+
     pub fn upsert_running(
         &mut self,
         task_id: i64,
@@ -201,6 +237,10 @@ impl PersistedStateStore {
                 workspace_name,
                 final_payload: PersistedFinalPayload::default(),
                 local_log_path: local_log_path.to_string_lossy().into_owned(),
+                started_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
             },
         );
     }
@@ -215,6 +255,7 @@ impl PersistedStateStore {
         stderr: String,
         result: HashMap<String, serde_json::Value>,
     ) {
+        let started_at = self.started_at(task_id);
         self.tasks.insert(
             task_id,
             PersistedTaskState {
@@ -223,6 +264,7 @@ impl PersistedStateStore {
                 workspace_name,
                 final_payload: PersistedFinalPayload::completed(exit_code, stdout, stderr, result),
                 local_log_path: local_log_path.to_string_lossy().into_owned(),
+                started_at,
             },
         );
     }
@@ -234,6 +276,7 @@ impl PersistedStateStore {
         local_log_path: PathBuf,
         error: String,
     ) {
+        let started_at = self.started_at(task_id);
         self.tasks.insert(
             task_id,
             PersistedTaskState {
@@ -242,10 +285,15 @@ impl PersistedStateStore {
                 workspace_name,
                 final_payload: PersistedFinalPayload::failed(error),
                 local_log_path: local_log_path.to_string_lossy().into_owned(),
+                started_at,
             },
         );
     }
 
+    fn started_at(&self, task_id: i64) -> u64 {
+        self.tasks.get(&task_id).map_or(0, |task| task.started_at)
+    }
+
     pub fn remove(&mut self, task_id: i64) -> Option<PersistedTaskState> {
         self.tasks.remove(&task_id)
     }
@@ -276,10 +324,11 @@ impl PersistedStateStore {
             version: CURRENT_STATE_VERSION,
             tasks: self.snapshot(),
         };
-        let serialized = serde_json::to_string_pretty(&payload).map_err(|e| {
+        let serialized = serde_json::to_vec_pretty(&payload).map_err(|e| {
             AgentError::Execution(format!("Failed to serialize persisted agent state: {}", e))
         })?;
-        fs::write(&self.state_file_path, serialized).map_err(|e| {
+        let serialized = at_rest::seal(self.cipher.as_ref(), serialized)?;
+        at_rest::write_atomic(&self.state_file_path, &serialized).map_err(|e| {
             AgentError::Execution(format!(
                 "Failed to write persisted agent state '{}': {}",
                 self.state_file_path.display(),
@@ -289,3 +338,75 @@ impl PersistedStateStore {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PersistedStateStore, PersistedTaskStateKind, STATE_DIR_NAME, STATE_FILE_NAME};
+    use crate::at_rest::{is_encrypted, AtRestCipher};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn encrypted_state_round_trips_and_is_not_plaintext() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let workspaces = std::env::temp_dir().join(format!("tasknexus_at_rest_test_{}", unique));
+        let cipher = AtRestCipher::from_key(&[7u8; 32]).unwrap();
+
+        let mut store =
+            PersistedStateStore::load_with_cipher(&workspaces, Some(cipher.clone())).unwrap();
+        store.mark_completed(
+            1,
+            "default".to_string(),
+            PathBuf::from("task_1.log"),
+            0,
+            "secret build output".to_string(),
+            String::new(),
+            HashMap::new(),
+        );
+        store.save().unwrap();
+
+        let raw = fs::read(workspaces.join(STATE_DIR_NAME).join(STATE_FILE_NAME)).unwrap();
+        assert!(is_encrypted(&raw));
+        assert!(!String::from_utf8_lossy(&raw).contains("secret build output"));
+        assert!(PersistedStateStore::load(&workspaces).is_err());
+
+        let reloaded = PersistedStateStore::load_with_cipher(&workspaces, Some(cipher)).unwrap();
+        let task = reloaded.get(1).expect("task should be restored");
+        assert_eq!(task.final_payload.stdout, "secret build output");
+        let _ = fs::remove_dir_all(&workspaces);
+    }
+
+    #[test]
+    fn running_task_at_custom_path_is_failed_after_restart() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("tasknexus_state_path_test_{}", unique));
+        let state_file = dir.join("state").join("running.json");
+
+        let mut store = PersistedStateStore::load_from_path(state_file.clone(), None).unwrap();
+        store.upsert_running(5, "default".to_string(), PathBuf::from("task_5.log"));
+        store.save().unwrap();
+        assert!(state_file.exists());
+        assert_eq!(
+            fs::read_dir(state_file.parent().unwrap()).unwrap().count(),
+            1
+        );
+
+        let mut reloaded = PersistedStateStore::load_from_path(state_file, None).unwrap();
+        assert!(reloaded.get(5).unwrap().started_at > 0);
+        assert!(reloaded.recover_after_restart());
+        assert_eq!(reloaded.fail_lost_tasks("agent restarted"), vec![5]);
+        let task = reloaded.get(5).unwrap();
+        assert_eq!(task.local_state, PersistedTaskStateKind::FailedPendingSync);
+        assert_eq!(task.final_payload.error, "agent restarted");
+        assert!(reloaded.fail_lost_tasks("agent restarted").is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,287 @@
+//! 任务资源预留
+//!
+//! 按任务声明的 CPU / 内存需求记账，避免 Agent 接收超出自身容量的任务。
+//! 另提供接收任务前的磁盘剩余空间检查，以及心跳上报的主机负载采样。
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use sysinfo::{Disks, System};
+
+/// 单个任务声明的资源需求
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceRequest {
+    /// CPU 核数，可以是小数
+    pub cpu: f64,
+    /// 内存字节数
+    pub memory_bytes: u64,
+}
+
+impl ResourceRequest {
+    pub fn new(cpu: Option<f64>, memory_bytes: Option<u64>) -> Self {
+        Self {
+            cpu: cpu.unwrap_or(0.0),
+            memory_bytes: memory_bytes.unwrap_or(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu <= 0.0 && self.memory_bytes == 0
+    }
+}
+
+/// 心跳上报的资源容量与剩余量
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceUsage {
+    pub cpu_capacity: f64,
+    pub cpu_free: f64,
+    pub memory_capacity_bytes: u64,
+    pub memory_free_bytes: u64,
+}
+
+/// Agent 的资源预算
+#[derive(Debug, Clone)]
+pub struct ResourceBudget {
+    cpu_capacity: f64,
+    memory_capacity: u64,
+    reserved_cpu: f64,
+    reserved_memory: u64,
+}
+
+impl ResourceBudget {
+    pub fn new(cpu_capacity: f64, memory_capacity: u64) -> Self {
+        Self {
+            cpu_capacity,
+            memory_capacity,
+            reserved_cpu: 0.0,
+            reserved_memory: 0,
+        }
+    }
+
+    /// 使用配置值，未配置时按本机 CPU 核数和物理内存总量
+    pub fn detect(cpu_capacity: Option<f64>, memory_capacity: Option<u64>) -> Self {
+        let cpu_capacity = cpu_capacity.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get() as f64)
+                .unwrap_or(1.0)
+        });
+        let memory_capacity = memory_capacity.unwrap_or_else(|| {
+            let mut sys = System::new();
+            sys.refresh_memory();
+            sys.total_memory()
+        });
+        Self::new(cpu_capacity, memory_capacity)
+    }
+
+    pub fn free_cpu(&self) -> f64 {
+        (self.cpu_capacity - self.reserved_cpu).max(0.0)
+    }
+
+    pub fn free_memory(&self) -> u64 {
+        self.memory_capacity.saturating_sub(self.reserved_memory)
+    }
+
+    /// 尝试预留资源，剩余量不足时返回拒绝原因
+    pub fn try_reserve(&mut self, request: &ResourceRequest) -> Result<(), String> {
+        if !request.cpu.is_finite() || request.cpu < 0.0 {
+            return Err(format!("Invalid cpu_request: {}", request.cpu));
+        }
+        if request.cpu > self.free_cpu() || request.memory_bytes > self.free_memory() {
+            return Err(format!(
+                "Insufficient agent resources: requested {} CPU / {} bytes memory, free {} CPU / {} bytes memory",
+                request.cpu,
+                request.memory_bytes,
+                self.free_cpu(),
+                self.free_memory()
+            ));
+        }
+
+        self.reserved_cpu += request.cpu;
+        self.reserved_memory += request.memory_bytes;
+        Ok(())
+    }
+
+    /// 释放任务结束后归还的资源
+    pub fn release(&mut self, request: &ResourceRequest) {
+        self.reserved_cpu = (self.reserved_cpu - request.cpu).max(0.0);
+        self.reserved_memory = self.reserved_memory.saturating_sub(request.memory_bytes);
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            cpu_capacity: self.cpu_capacity,
+            cpu_free: self.free_cpu(),
+            memory_capacity_bytes: self.memory_capacity,
+            memory_free_bytes: self.free_memory(),
+        }
+    }
+}
+
+/// 心跳上报的主机当前负载
+#[derive(Debug, Clone, Serialize)]
+pub struct HostLoad {
+    /// 全部 CPU 的平均使用率（0-100），基于与上一次采样之间的间隔
+    pub cpu_usage_percent: f32,
+    pub memory_used_bytes: u64,
+    pub memory_total_bytes: u64,
+    /// 1/5/15 分钟平均负载（仅 Unix）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_average: Option<[f64; 3]>,
+}
+
+/// 主机负载采样器
+///
+/// 复用同一个 `System` 实例，每次只刷新 CPU 使用率与内存，避免每次心跳都执行 `refresh_all`。
+pub struct HostLoadSampler {
+    sys: System,
+}
+
+impl HostLoadSampler {
+    pub fn new() -> Self {
+        let mut sys = System::new();
+        // CPU 使用率按两次刷新之间计算，先建立基线
+        sys.refresh_cpu_usage();
+        sys.refresh_memory();
+        Self { sys }
+    }
+
+    pub fn sample(&mut self) -> HostLoad {
+        self.sys.refresh_cpu_usage();
+        self.sys.refresh_memory();
+        let load_average = if cfg!(unix) {
+            let load = System::load_average();
+            Some([load.one, load.five, load.fifteen])
+        } else {
+            None
+        };
+        HostLoad {
+            cpu_usage_percent: self.sys.global_cpu_info().cpu_usage(),
+            memory_used_bytes: self.sys.used_memory(),
+            memory_total_bytes: self.sys.total_memory(),
+            load_average,
+        }
+    }
+}
+
+impl Default for HostLoadSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 查询 `path` 所在磁盘的可用字节数，无法确定挂载点时返回 None
+pub fn available_disk_bytes(path: &Path) -> Option<u64> {
+    // 工作空间目录可能尚未创建，取最近的已存在祖先目录
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let resolved = existing
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(existing));
+
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| resolved.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// 检查 `path` 所在磁盘的可用空间是否不低于 `min_free_bytes`，不足时返回拒绝原因
+///
+/// `min_free_bytes` 为 0 或无法测量可用空间时视为通过。
+pub fn check_free_disk(path: &Path, min_free_bytes: u64) -> Result<(), String> {
+    if min_free_bytes == 0 {
+        return Ok(());
+    }
+    match available_disk_bytes(path) {
+        Some(free) if free < min_free_bytes => Err(format!(
+            "insufficient disk space: {} bytes free on {}, {} bytes required",
+            free,
+            path.display(),
+            min_free_bytes
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        available_disk_bytes, check_free_disk, HostLoadSampler, ResourceBudget, ResourceRequest,
+    };
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn try_reserve_rejects_request_exceeding_free_resources() {
+        let mut budget = ResourceBudget::new(4.0, 8 * GIB);
+
+        assert!(budget
+            .try_reserve(&ResourceRequest::new(Some(8.0), None))
+            .is_err());
+        assert!(budget
+            .try_reserve(&ResourceRequest::new(Some(1.0), Some(16 * GIB)))
+            .is_err());
+
+        let request = ResourceRequest::new(Some(4.0), Some(8 * GIB));
+        assert!(budget.try_reserve(&request).is_ok());
+        assert!(budget
+            .try_reserve(&ResourceRequest::new(Some(0.5), None))
+            .is_err());
+
+        budget.release(&request);
+        assert_eq!(budget.free_cpu(), 4.0);
+        assert_eq!(budget.free_memory(), 8 * GIB);
+    }
+
+    #[tokio::test]
+    async fn concurrent_reservations_respect_budget() {
+        let budget = Arc::new(Mutex::new(ResourceBudget::new(4.0, 8 * GIB)));
+        let request = ResourceRequest::new(Some(1.0), Some(2 * GIB));
+
+        let handles = (0..10)
+            .map(|_| {
+                let budget = budget.clone();
+                tokio::spawn(async move { budget.lock().await.try_reserve(&request).is_ok() })
+            })
+            .collect::<Vec<_>>();
+
+        let mut accepted = 0;
+        for handle in handles {
+            if handle.await.unwrap() {
+                accepted += 1;
+            }
+        }
+
+        assert_eq!(accepted, 4);
+        let usage = budget.lock().await.usage();
+        assert_eq!(usage.cpu_free, 0.0);
+        assert_eq!(usage.memory_free_bytes, 0);
+    }
+
+    #[test]
+    fn host_load_sampler_reports_cpu_and_memory() {
+        let mut sampler = HostLoadSampler::new();
+        let load = sampler.sample();
+
+        assert!(load.memory_total_bytes > 0);
+        assert!(load.memory_used_bytes <= load.memory_total_bytes);
+        assert!((0.0..=100.0).contains(&load.cpu_usage_percent));
+        assert_eq!(load.load_average.is_some(), cfg!(unix));
+    }
+
+    #[test]
+    fn check_free_disk_reports_measured_free_bytes() {
+        let dir = std::env::temp_dir().join("tasknexus_disk_check_missing_dir");
+        assert!(check_free_disk(&dir, 0).is_ok());
+
+        let Some(free) = available_disk_bytes(&dir) else {
+            return;
+        };
+        assert!(check_free_disk(&dir, 1).is_ok());
+        let reason = check_free_disk(&dir, u64::MAX).unwrap_err();
+        assert!(reason.starts_with("insufficient disk space"));
+        assert!(reason.contains(&format!("{} bytes free", free)));
+    }
+}
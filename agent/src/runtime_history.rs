@@ -0,0 +1,282 @@
+//! 任务历史耗时记录
+//!
+//! 按任务签名记录最近若干次成功执行的耗时，用于推导自适应超时。
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::at_rest::{self, AtRestCipher};
+use crate::error::{AgentError, Result};
+use crate::persisted_state::STATE_DIR_NAME;
+
+const HISTORY_FILE_NAME: &str = "runtime_history.json";
+const MAX_SAMPLES_PER_TASK: usize = 20;
+/// 最多记录的任务签名数，超出时淘汰最久未执行的签名
+const MAX_TRACKED_SIGNATURES: usize = 1000;
+const MIN_ADAPTIVE_TIMEOUT_SECS: u64 = 60;
+
+/// 根据工作空间、执行模式和命令/代码内容计算任务签名
+pub fn task_signature(workspace_name: &str, execution_mode: &str, command: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(workspace_name.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(execution_mode.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(command.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct RuntimeHistoryFile {
+    #[serde(default)]
+    durations_ms: HashMap<String, VecDeque<u64>>,
+    /// 签名按最近一次记录的时间排列，最旧的在前
+    #[serde(default)]
+    recency: VecDeque<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeHistoryStore {
+    history_file_path: PathBuf,
+    durations_ms: HashMap<String, VecDeque<u64>>,
+    recency: VecDeque<String>,
+    cipher: Option<AtRestCipher>,
+}
+
+impl RuntimeHistoryStore {
+    pub fn new(workspaces_path: &Path) -> Self {
+        Self {
+            history_file_path: workspaces_path.join(STATE_DIR_NAME).join(HISTORY_FILE_NAME),
+            durations_ms: HashMap::new(),
+            recency: VecDeque::new(),
+            cipher: None,
+        }
+    }
+
+    /// 配置了加密器时以密文形式落盘
+    pub fn with_cipher(mut self, cipher: Option<AtRestCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn load(workspaces_path: &Path) -> Result<Self> {
+        Self::load_with_cipher(workspaces_path, None)
+    }
+
+    pub fn load_with_cipher(workspaces_path: &Path, cipher: Option<AtRestCipher>) -> Result<Self> {
+        let mut store = Self::new(workspaces_path).with_cipher(cipher);
+        if !store.history_file_path.exists() {
+            return Ok(store);
+        }
+
+        let content = fs::read(&store.history_file_path).map_err(|e| {
+            AgentError::Execution(format!(
+                "Failed to read runtime history '{}': {}",
+                store.history_file_path.display(),
+                e
+            ))
+        })?;
+        let content = at_rest::open(store.cipher.as_ref(), content)?;
+        let decoded: RuntimeHistoryFile = serde_json::from_slice(&content).map_err(|e| {
+            AgentError::Execution(format!(
+                "Failed to parse runtime history '{}': {}",
+                store.history_file_path.display(),
+                e
+            ))
+        })?;
+        store.durations_ms = decoded.durations_ms;
+        // 旧文件没有记录顺序，未出现在顺序中的签名视为最旧
+        let mut recency = decoded
+            .recency
+            .into_iter()
+            .filter(|signature| store.durations_ms.contains_key(signature))
+            .collect::<VecDeque<_>>();
+        let listed = recency.iter().cloned().collect::<HashSet<_>>();
+        for signature in store.durations_ms.keys() {
+            if !listed.contains(signature) {
+                recency.push_front(signature.clone());
+            }
+        }
+        store.recency = recency;
+        store.evict_oldest_signatures();
+        Ok(store)
+    }
+
+    /// 记录一次成功执行的耗时，每个签名只保留最近若干条，签名总数超出上限时淘汰最久未执行的
+    pub fn record(&mut self, signature: &str, duration_ms: u64) {
+        let samples = self.durations_ms.entry(signature.to_string()).or_default();
+        samples.push_back(duration_ms);
+        while samples.len() > MAX_SAMPLES_PER_TASK {
+            samples.pop_front();
+        }
+
+        if let Some(index) = self.recency.iter().position(|s| s == signature) {
+            self.recency.remove(index);
+        }
+        self.recency.push_back(signature.to_string());
+        self.evict_oldest_signatures();
+    }
+
+    fn evict_oldest_signatures(&mut self) {
+        while self.recency.len() > MAX_TRACKED_SIGNATURES {
+            if let Some(oldest) = self.recency.pop_front() {
+                self.durations_ms.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn median_ms(&self, signature: &str) -> Option<u64> {
+        let samples = self.durations_ms.get(signature)?;
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        Some(sorted[sorted.len() / 2])
+    }
+
+    /// 历史耗时中位数乘以倍数得到的超时(秒)，无历史时返回 None
+    pub fn adaptive_timeout_secs(&self, signature: &str, multiplier: f64) -> Option<u64> {
+        let median_ms = self.median_ms(signature)?;
+        let timeout_secs = (median_ms as f64 * multiplier / 1000.0).ceil() as u64;
+        Some(timeout_secs.max(MIN_ADAPTIVE_TIMEOUT_SECS))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let parent = self.history_file_path.parent().ok_or_else(|| {
+            AgentError::Execution("Runtime history path has no parent directory".to_string())
+        })?;
+        fs::create_dir_all(parent).map_err(|e| {
+            AgentError::Execution(format!(
+                "Failed to create runtime history directory '{}': {}",
+                parent.display(),
+                e
+            ))
+        })?;
+
+        let payload = RuntimeHistoryFile {
+            durations_ms: self.durations_ms.clone(),
+            recency: self.recency.clone(),
+        };
+        let serialized = serde_json::to_vec(&payload).map_err(|e| {
+            AgentError::Execution(format!("Failed to serialize runtime history: {}", e))
+        })?;
+        let serialized = at_rest::seal(self.cipher.as_ref(), serialized)?;
+        at_rest::write_atomic(&self.history_file_path, &serialized).map_err(|e| {
+            AgentError::Execution(format!(
+                "Failed to write runtime history '{}': {}",
+                self.history_file_path.display(),
+                e
+            ))
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{task_signature, RuntimeHistoryStore, HISTORY_FILE_NAME, MAX_TRACKED_SIGNATURES};
+    use crate::at_rest::{is_encrypted, AtRestCipher};
+    use crate::persisted_state::STATE_DIR_NAME;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn adaptive_timeout_uses_multiple_of_median_and_survives_reload() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let workspaces_path =
+            std::env::temp_dir().join(format!("tasknexus_history_test_{}", unique));
+        let signature = task_signature("default", "command", "build.sh");
+
+        let mut store = RuntimeHistoryStore::new(&workspaces_path);
+        assert_eq!(store.adaptive_timeout_secs(&signature, 3.0), None);
+        for duration_ms in [100_000, 300_000, 200_000] {
+            store.record(&signature, duration_ms);
+        }
+        store.save().unwrap();
+        // 通过临时文件重命名写入，不留下临时文件
+        let temp_path = workspaces_path
+            .join(STATE_DIR_NAME)
+            .join(format!("{}.tmp", HISTORY_FILE_NAME));
+        assert!(!temp_path.exists());
+
+        let reloaded = RuntimeHistoryStore::load(&workspaces_path).unwrap();
+        assert_eq!(reloaded.median_ms(&signature), Some(200_000));
+        assert_eq!(reloaded.adaptive_timeout_secs(&signature, 3.0), Some(600));
+        assert_eq!(
+            reloaded.adaptive_timeout_secs(&task_signature("default", "command", "other.sh"), 3.0),
+            None
+        );
+
+        let _ = std::fs::remove_dir_all(&workspaces_path);
+    }
+
+    #[test]
+    fn record_evicts_least_recently_recorded_signatures() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let workspaces_path =
+            std::env::temp_dir().join(format!("tasknexus_history_evict_test_{}", unique));
+        let signature = |i: usize| task_signature("default", "command", &format!("job{}.sh", i));
+
+        let mut store = RuntimeHistoryStore::new(&workspaces_path);
+        for i in 0..MAX_TRACKED_SIGNATURES {
+            store.record(&signature(i), 1_000);
+        }
+        // 再次执行的签名变为最新，超出上限时淘汰的是 job1
+        store.record(&signature(0), 2_000);
+        store.record(&signature(MAX_TRACKED_SIGNATURES), 3_000);
+        assert_eq!(store.median_ms(&signature(1)), None);
+        assert_eq!(store.median_ms(&signature(0)), Some(2_000));
+        store.save().unwrap();
+
+        let mut reloaded = RuntimeHistoryStore::load(&workspaces_path).unwrap();
+        assert_eq!(
+            reloaded.median_ms(&signature(MAX_TRACKED_SIGNATURES)),
+            Some(3_000)
+        );
+        reloaded.record(&signature(MAX_TRACKED_SIGNATURES + 1), 4_000);
+        assert_eq!(reloaded.median_ms(&signature(2)), None);
+        assert_eq!(reloaded.median_ms(&signature(0)), Some(2_000));
+
+        let _ = std::fs::remove_dir_all(&workspaces_path);
+    }
+
+    #[test]
+    fn runtime_history_is_encrypted_at_rest_when_cipher_is_configured() {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        let workspaces_path =
+            std::env::temp_dir().join(format!("tasknexus_history_cipher_test_{}", unique));
+        let cipher = AtRestCipher::from_key(&[9u8; 32]).unwrap();
+        let signature = task_signature("default", "command", "secret.sh");
+
+        let mut store =
+            RuntimeHistoryStore::new(&workspaces_path).with_cipher(Some(cipher.clone()));
+        store.record(&signature, 120_000);
+        store.save().unwrap();
+
+        let raw =
+            std::fs::read(workspaces_path.join(STATE_DIR_NAME).join(HISTORY_FILE_NAME)).unwrap();
+        assert!(is_encrypted(&raw));
+        assert!(RuntimeHistoryStore::load(&workspaces_path).is_err());
+        let reloaded =
+            RuntimeHistoryStore::load_with_cipher(&workspaces_path, Some(cipher)).unwrap();
+        assert_eq!(reloaded.median_ms(&signature), Some(120_000));
+
+        let _ = std::fs::remove_dir_all(&workspaces_path);
+    }
+}
@@ -94,10 +94,7 @@ pub fn cleanup_previous_update() {
             let bak_path = parent.join(format!("{}.bak", file_name));
             if bak_path.exists() {
                 match std::fs::remove_file(&bak_path) {
-                    Ok(_) => info!(
-                        "Cleaned up previous update backup: {:?}",
-                        bak_path
-                    ),
+                    Ok(_) => info!("Cleaned up previous update backup: {:?}", bak_path),
                     Err(e) => warn!(
                         "Failed to clean up previous update backup {:?}: {}",
                         bak_path, e
@@ -129,9 +126,7 @@ fn replace_current_binary(new_binary: &Path) -> Result<()> {
     })?;
     let file_name = current_exe
         .file_name()
-        .ok_or_else(|| {
-            AgentError::Execution("Current executable has no file name".to_string())
-        })?
+        .ok_or_else(|| AgentError::Execution("Current executable has no file name".to_string()))?
         .to_string_lossy()
         .to_string();
 
@@ -69,6 +69,16 @@ pub enum ServiceAction {
         #[arg(short, long, default_value = DEFAULT_SERVICE_NAME)]
         name: String,
     },
+    /// 输出 systemd unit 文件内容到 stdout（不安装）
+    #[cfg(target_os = "linux")]
+    Unit {
+        /// 配置文件路径
+        #[arg(short, long)]
+        config: PathBuf,
+        /// 服务名称
+        #[arg(short, long, default_value = DEFAULT_SERVICE_NAME)]
+        name: String,
+    },
 }
 
 /// 根据服务名称生成显示名称。
@@ -109,6 +119,11 @@ pub fn handle_service_command(action: ServiceAction) {
             platform::stop_service(&name).and_then(|_| platform::start_service(&name))
         }
         ServiceAction::Status { name } => platform::query_service_status(&name),
+        #[cfg(target_os = "linux")]
+        ServiceAction::Unit { config, name } => {
+            let config_path = std::fs::canonicalize(&config).unwrap_or(config);
+            platform::print_unit(&name, &config_path)
+        }
     };
 
     match result {
@@ -120,6 +135,11 @@ pub fn handle_service_command(action: ServiceAction) {
     }
 }
 
+/// 查询服务的开机自启与运行状态，返回简短描述（用于 `doctor` 诊断）
+pub fn autostart_status(service_name: &str) -> String {
+    platform::autostart_status(service_name)
+}
+
 /// 通过服务管理器重启当前服务（用于自更新后）。
 ///
 /// 不同平台实现不同：
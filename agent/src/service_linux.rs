@@ -2,7 +2,7 @@
 //!
 //! 通过生成 systemd unit 文件实现服务的安装、卸载、启停和状态查询。
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use super::{display_name_for, SERVICE_DESCRIPTION};
 
@@ -17,31 +17,32 @@ fn unit_name(service_name: &str) -> String {
     format!("{}.service", service_name)
 }
 
-/// 安装 systemd 服务
-pub fn install_service(service_name: &str, config_path: &Path) -> Result<(), BoxError> {
-    let current_exe = std::env::current_exe()?;
-    let exe_path = current_exe.to_string_lossy();
-    let config_str = config_path.to_string_lossy();
-    let display_name = display_name_for(service_name);
-    let unit_path = unit_file_path(service_name);
-    let unit = unit_name(service_name);
+/// 检测 exe 同级目录下是否存在 .venv
+fn detect_venv(exe_path: &Path) -> Option<PathBuf> {
+    exe_path
+        .parent()
+        .map(|exe_dir| exe_dir.join(".venv"))
+        .filter(|venv_path| venv_path.exists())
+}
 
-    // 检测 exe 同级目录下是否存在 .venv
-    let mut env_lines = String::new();
-    if let Some(exe_dir) = current_exe.parent() {
-        let venv_path = exe_dir.join(".venv");
-        if venv_path.exists() {
-            let venv_str = venv_path.to_string_lossy();
-            env_lines = format!(
-                r#"Environment="VIRTUAL_ENV={venv}"
+/// 渲染 systemd unit 文件内容
+fn render_unit(
+    service_name: &str,
+    exe_path: &Path,
+    config_path: &Path,
+    venv_path: Option<&Path>,
+) -> String {
+    let env_lines = match venv_path {
+        Some(venv_path) => format!(
+            r#"Environment="VIRTUAL_ENV={venv}"
 Environment="PATH={venv}/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
 "#,
-                venv = venv_str
-            );
-        }
-    }
+            venv = venv_path.to_string_lossy()
+        ),
+        None => String::new(),
+    };
 
-    let unit_content = format!(
+    format!(
         r#"[Unit]
 Description={description}
 After=network-online.target
@@ -59,10 +60,42 @@ TimeoutStopSec=30
 WantedBy=multi-user.target
 "#,
         description = SERVICE_DESCRIPTION,
-        exe = exe_path,
-        config = config_str,
+        exe = exe_path.to_string_lossy(),
+        config = config_path.to_string_lossy(),
         svc_name = service_name,
         env_lines = env_lines,
+    )
+}
+
+/// 输出 systemd unit 文件内容（不安装），便于自定义后手动部署
+pub fn print_unit(service_name: &str, config_path: &Path) -> Result<(), BoxError> {
+    let current_exe = std::env::current_exe()?;
+    let venv_path = detect_venv(&current_exe);
+    print!(
+        "{}",
+        render_unit(
+            service_name,
+            &current_exe,
+            config_path,
+            venv_path.as_deref()
+        )
+    );
+    Ok(())
+}
+
+/// 安装 systemd 服务
+pub fn install_service(service_name: &str, config_path: &Path) -> Result<(), BoxError> {
+    let current_exe = std::env::current_exe()?;
+    let config_str = config_path.to_string_lossy();
+    let display_name = display_name_for(service_name);
+    let unit_path = unit_file_path(service_name);
+    let unit = unit_name(service_name);
+    let venv_path = detect_venv(&current_exe);
+    let unit_content = render_unit(
+        service_name,
+        &current_exe,
+        config_path,
+        venv_path.as_deref(),
     );
 
     // 写入 unit 文件
@@ -89,7 +122,7 @@ WantedBy=multi-user.target
     println!("  Unit 文件: {}", unit_path);
     println!("  配置文件:  {}", config_str);
     println!("  启动类型:  自动 (on boot)");
-    if !env_lines.is_empty() {
+    if venv_path.is_some() {
         println!("  虚拟环境:  已自动检测并注入");
     }
     println!();
@@ -176,6 +209,22 @@ pub fn query_service_status(service_name: &str) -> Result<(), BoxError> {
     Ok(())
 }
 
+/// 查询开机自启与运行状态（不输出到终端），用于诊断
+pub fn autostart_status(service_name: &str) -> String {
+    if !Path::new(&unit_file_path(service_name)).exists() {
+        return "未安装".to_string();
+    }
+    let unit = unit_name(service_name);
+    let query = |verb: &str| {
+        std::process::Command::new("systemctl")
+            .args([verb, &unit])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_else(|e| format!("unknown ({})", e))
+    };
+    format!("{} / {}", query("is-enabled"), query("is-active"))
+}
+
 /// 通过 systemd 重启服务
 pub fn restart_via_service_manager() {
     // 使用非零退出码让 systemd 的 Restart=on-failure 策略自动重启
@@ -202,3 +251,25 @@ fn run_systemctl(args: &[&str]) -> Result<(), BoxError> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::render_unit;
+    use std::path::Path;
+
+    #[test]
+    fn render_unit_contains_exe_and_config_paths() {
+        let unit = render_unit(
+            "tasknexus-build",
+            Path::new("/opt/tasknexus/tasknexus-agent"),
+            Path::new("/etc/tasknexus/config.yaml"),
+            Some(Path::new("/opt/tasknexus/.venv")),
+        );
+
+        assert!(unit.contains(
+            "ExecStart=/opt/tasknexus/tasknexus-agent run --config /etc/tasknexus/config.yaml --service-name tasknexus-build"
+        ));
+        assert!(unit.contains("Environment=\"VIRTUAL_ENV=/opt/tasknexus/.venv\""));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+    }
+}
@@ -174,6 +174,23 @@ pub fn query_service_status(service_name: &str) -> Result<(), BoxError> {
     Ok(())
 }
 
+/// 查询开机自启状态（不输出到终端），用于诊断
+pub fn autostart_status(service_name: &str) -> String {
+    if !Path::new(&plist_path(service_name)).exists() {
+        return "未安装".to_string();
+    }
+    let loaded = std::process::Command::new("launchctl")
+        .args(["list", &plist_label(service_name)])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if loaded {
+        "已安装 / 已加载".to_string()
+    } else {
+        "已安装 / 未加载".to_string()
+    }
+}
+
 /// 通过 launchd 重启服务
 pub fn restart_via_service_manager() {
     // 使用非零退出码让 launchd 的 KeepAlive/SuccessfulExit 策略自动重启
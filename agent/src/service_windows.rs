@@ -127,8 +127,7 @@ pub fn uninstall_service(service_name: &str) -> Result<(), BoxError> {
     #[cfg(windows)]
     {
         let display_name = display_name_for(service_name);
-        let manager =
-            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
         let service = manager.open_service(
             service_name,
             ServiceAccess::STOP | ServiceAccess::DELETE | ServiceAccess::QUERY_STATUS,
@@ -172,8 +171,7 @@ pub fn start_service(service_name: &str) -> Result<(), BoxError> {
     #[cfg(windows)]
     {
         let display_name = display_name_for(service_name);
-        let manager =
-            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
         let service = manager.open_service(
             service_name,
             ServiceAccess::START | ServiceAccess::QUERY_STATUS,
@@ -207,9 +205,7 @@ pub fn start_service(service_name: &str) -> Result<(), BoxError> {
                 }
                 _ => {
                     if std::time::Instant::now() >= deadline {
-                        return Err(
-                            format!("等待服务 '{}' 启动超时", display_name).into()
-                        );
+                        return Err(format!("等待服务 '{}' 启动超时", display_name).into());
                     }
                 }
             }
@@ -228,8 +224,7 @@ pub fn stop_service(service_name: &str) -> Result<(), BoxError> {
     #[cfg(windows)]
     {
         let display_name = display_name_for(service_name);
-        let manager =
-            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
         let service = manager.open_service(
             service_name,
             ServiceAccess::STOP | ServiceAccess::QUERY_STATUS,
@@ -271,8 +266,7 @@ pub fn query_service_status(service_name: &str) -> Result<(), BoxError> {
     #[cfg(windows)]
     {
         let display_name = display_name_for(service_name);
-        let manager =
-            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
         let service = manager.open_service(service_name, ServiceAccess::QUERY_STATUS)?;
         let status = service.query_status()?;
 
@@ -295,6 +289,40 @@ pub fn query_service_status(service_name: &str) -> Result<(), BoxError> {
     }
 }
 
+/// 查询服务启动类型与运行状态（不输出到终端），用于诊断
+pub fn autostart_status(service_name: &str) -> String {
+    #[cfg(not(windows))]
+    {
+        let _ = service_name;
+        "unsupported".to_string()
+    }
+
+    #[cfg(windows)]
+    {
+        let query = || -> Result<String, BoxError> {
+            let manager =
+                ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+            let service = manager.open_service(
+                service_name,
+                ServiceAccess::QUERY_STATUS | ServiceAccess::QUERY_CONFIG,
+            )?;
+            let start_type = match service.query_config()?.start_type {
+                ServiceStartType::AutoStart => "自动启动",
+                ServiceStartType::OnDemand => "手动启动",
+                ServiceStartType::Disabled => "已禁用",
+                _ => "其他",
+            };
+            let state = if service.query_status()?.current_state == ServiceState::Running {
+                "运行中"
+            } else {
+                "未运行"
+            };
+            Ok(format!("{} / {}", start_type, state))
+        };
+        query().unwrap_or_else(|_| "未安装".to_string())
+    }
+}
+
 /// 通过服务管理器重启（在自更新场景中使用非零退出码触发）
 pub fn restart_via_service_manager() {
     // SCM 的故障恢复策略会在进程非零退出后自动重启
@@ -0,0 +1,245 @@
+//! 本机任务日志
+//!
+//! 配置 `task_log_dir` 后，`TaskRunner` 将每个任务的 stdout/stderr 逐行写入 `<task_log_dir>/<task_id>.log`，
+//! 每行带时间戳与流标记，服务器丢失日志时仍可在 Agent 主机上查看完整输出。
+//! 新建日志时按 `task_log_retention` 清理旧日志；配置 `at_rest_encryption_key` 时日志加密存储。
+
+use crate::at_rest::{AtRestCipher, LogFileWriter};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+const TASK_LOG_EXTENSION: &str = "log";
+
+/// 本机任务日志的保留策略，新建日志时执行
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TaskLogRetention {
+    /// 最多保留的日志文件数，超出时删除最旧的 (0 表示不限制)
+    pub max_files: usize,
+    /// 删除修改时间早于该天数的日志 (0 表示不限制)
+    pub max_age_days: u64,
+}
+
+impl Default for TaskLogRetention {
+    fn default() -> Self {
+        Self {
+            max_files: 500,
+            max_age_days: 30,
+        }
+    }
+}
+
+/// 单个任务的日志文件，可在输出回调之间共享
+///
+/// 输出片段按行写入；未以换行结束的片段暂存，等待同一流的后续输出。
+/// [`TaskLogFile::finish`] 或最后一个实例被 drop 时写出残留内容并刷新文件。
+#[derive(Clone)]
+pub(crate) struct TaskLogFile {
+    inner: Arc<Mutex<TaskLogWriter>>,
+}
+
+struct TaskLogWriter {
+    writer: Option<LogFileWriter>,
+    stdout_partial: String,
+    stderr_partial: String,
+}
+
+impl TaskLogFile {
+    /// 清理旧日志后创建 `<dir>/<task_id>.log`，已存在时覆盖
+    pub(crate) fn create(
+        dir: &Path,
+        task_id: i64,
+        retention: TaskLogRetention,
+        cipher: Option<&AtRestCipher>,
+    ) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        prune_task_logs(dir, retention.max_files.saturating_sub(1), retention);
+        let writer = LogFileWriter::create(&task_log_path(dir, task_id), cipher)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(TaskLogWriter {
+                writer: Some(writer),
+                stdout_partial: String::new(),
+                stderr_partial: String::new(),
+            })),
+        })
+    }
+
+    pub(crate) fn write_chunk(&self, chunk: &str, is_stderr: bool) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let TaskLogWriter {
+            writer,
+            stdout_partial,
+            stderr_partial,
+        } = &mut *inner;
+        let Some(writer) = writer.as_mut() else {
+            return;
+        };
+        let partial = if is_stderr {
+            stderr_partial
+        } else {
+            stdout_partial
+        };
+        partial.push_str(chunk);
+        while let Some(end) = partial.find(['\n', '\r']) {
+            let line: String = partial.drain(..=end).collect();
+            write_line(writer, &line[..end], is_stderr);
+        }
+    }
+
+    /// 写出未结束的行并刷新关闭文件，之后的输出被忽略
+    pub(crate) fn finish(&self) {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).close();
+    }
+}
+
+impl TaskLogWriter {
+    fn close(&mut self) {
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+        for (partial, is_stderr) in [
+            (std::mem::take(&mut self.stdout_partial), false),
+            (std::mem::take(&mut self.stderr_partial), true),
+        ] {
+            if !partial.is_empty() {
+                write_line(&mut writer, &partial, is_stderr);
+            }
+        }
+        if let Err(e) = writer.flush() {
+            warn!("Failed to flush task log: {}", e);
+        }
+    }
+}
+
+impl Drop for TaskLogWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn write_line(writer: &mut LogFileWriter, line: &str, is_stderr: bool) {
+    let stream = if is_stderr { "stderr" } else { "stdout" };
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let record = format!("{} [{}] {}\n", timestamp, stream, line);
+    if let Err(e) = writer.write_all(record.as_bytes()) {
+        warn!("Failed to write task log: {}", e);
+    }
+}
+
+pub fn task_log_path(dir: &Path, task_id: i64) -> PathBuf {
+    dir.join(format!("{}.{}", task_id, TASK_LOG_EXTENSION))
+}
+
+/// 删除过期日志，并只保留最新的 `keep` 个（`max_files` 为 0 时不按数量清理）
+fn prune_task_logs(dir: &Path, keep: usize, retention: TaskLogRetention) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut logs: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path.extension().and_then(|ext| ext.to_str()) == Some(TASK_LOG_EXTENSION)
+        })
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+            Some((modified, path))
+        })
+        .collect();
+    // 最新的在前
+    logs.sort_by_key(|log| std::cmp::Reverse(log.0));
+
+    let max_age = Duration::from_secs(retention.max_age_days.saturating_mul(24 * 60 * 60));
+    let now = SystemTime::now();
+    for (index, (modified, path)) in logs.iter().enumerate() {
+        let expired = retention.max_age_days > 0
+            && now.duration_since(*modified).unwrap_or_default() > max_age;
+        let over_count = retention.max_files > 0 && index >= keep;
+        if expired || over_count {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove old task log {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{task_log_path, TaskLogFile, TaskLogRetention};
+    use crate::at_rest::{read_log, AtRestCipher};
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_dir(prefix: &str) -> std::path::PathBuf {
+        let unique = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+        std::env::temp_dir().join(format!("{}_{}", prefix, unique))
+    }
+
+    #[test]
+    fn task_log_writes_prefixed_lines_and_flushes_partial_on_drop() {
+        let dir = unique_temp_dir("tasknexus_task_log_test");
+        let log = TaskLogFile::create(&dir, 7, TaskLogRetention::default(), None).unwrap();
+        log.write_chunk("out ", false);
+        log.write_chunk("err\n", true);
+        log.write_chunk("line\nunfinished", false);
+        drop(log);
+
+        let content = fs::read_to_string(task_log_path(&dir, 7)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", content);
+        assert!(lines[0].ends_with(" [stderr] err"), "{}", lines[0]);
+        assert!(lines[1].ends_with(" [stdout] out line"), "{}", lines[1]);
+        assert!(lines[2].ends_with(" [stdout] unfinished"), "{}", lines[2]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn task_log_is_encrypted_at_rest_when_cipher_is_configured() {
+        let dir = unique_temp_dir("tasknexus_task_log_cipher_test");
+        let cipher = AtRestCipher::from_key(&[3u8; 32]).unwrap();
+        let log = TaskLogFile::create(&dir, 8, TaskLogRetention::default(), Some(&cipher)).unwrap();
+        log.write_chunk("password=hunter2\n", false);
+        log.finish();
+
+        let raw = fs::read(task_log_path(&dir, 8)).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("hunter2"));
+        assert!(read_log(&task_log_path(&dir, 8), None).is_err());
+        let content =
+            String::from_utf8(read_log(&task_log_path(&dir, 8), Some(&cipher)).unwrap()).unwrap();
+        assert!(
+            content.ends_with(" [stdout] password=hunter2\n"),
+            "{}",
+            content
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn task_log_retention_keeps_newest_files() {
+        let dir = unique_temp_dir("tasknexus_task_log_retention_test");
+        let retention = TaskLogRetention {
+            max_files: 2,
+            max_age_days: 0,
+        };
+        for task_id in 1..=3 {
+            TaskLogFile::create(&dir, task_id, retention, None)
+                .unwrap()
+                .finish();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+
+        assert!(!task_log_path(&dir, 1).exists());
+        assert!(task_log_path(&dir, 2).exists());
+        assert!(task_log_path(&dir, 3).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}